@@ -717,3 +717,42 @@ lazy_static! {
         m
     };
 }
+
+const ANSI_RGB: [(u8, u8, u8); 16] = [
+    (0x00, 0x00, 0x00), (0xcd, 0x00, 0x00), (0x00, 0xcd, 0x00), (0xcd, 0xcd, 0x00),
+    (0x00, 0x00, 0xee), (0xcd, 0x00, 0xcd), (0x00, 0xcd, 0xcd), (0xe5, 0xe5, 0xe5),
+    (0x7f, 0x7f, 0x7f), (0xff, 0x00, 0x00), (0x00, 0xff, 0x00), (0xff, 0xff, 0x00),
+    (0x5c, 0x5c, 0xff), (0xff, 0x00, 0xff), (0x00, 0xff, 0xff), (0xff, 0xff, 0xff),
+];
+
+const CUBE_STEPS: [u8; 6] = [0x00, 0x5f, 0x87, 0xaf, 0xd7, 0xff];
+
+// rgb value of xterm-256 colour @n
+pub fn xterm_rgb(n: u8) -> (u8, u8, u8) {
+    match n {
+        0..=15 => ANSI_RGB[n as usize],
+        16..=231 => {
+            let n = n - 16;
+            (CUBE_STEPS[(n / 36) as usize], CUBE_STEPS[(n / 6 % 6) as usize], CUBE_STEPS[(n % 6) as usize])
+        },
+        _ => {
+            let grey = 8 + (n - 232) * 10;
+            (grey, grey, grey)
+        },
+    }
+}
+
+// index of the colour in 0..@count (or 16..256 if @count is 256) closest to @rgb
+pub fn nearest(rgb: (u8, u8, u8), count: u16) -> u8 {
+    // the first 16 are themeable in the terminal, so avoid them if we can
+    let start = if count > 16 { 16 } else { 0 };
+    let distance = |n: u8| {
+        let (r, g, b) = xterm_rgb(n);
+        let dr = i32::from(r) - i32::from(rgb.0);
+        let dg = i32::from(g) - i32::from(rgb.1);
+        let db = i32::from(b) - i32::from(rgb.2);
+        // weighted for how sensitive eyes are to each channel
+        2*dr*dr + 4*dg*dg + 3*db*db
+    };
+    (start..count).map(|n| n as u8).min_by_key(|&n| distance(n)).unwrap()
+}
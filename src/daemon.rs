@@ -0,0 +1,146 @@
+extern crate rmp_serde;
+
+use std::env;
+use std::fs::{self, File, DirBuilder};
+use std::io::{self, Read, Write, BufWriter};
+use std::net::Shutdown;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, MetadataExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use nix;
+use nvim;
+use poller;
+
+// each connection is one file:
+// the client sends (filename, filetype), then the file contents, then shuts down its write half.
+// the daemon streams the highlighted output back over the same connection
+type Header = (String, Option<String>);
+
+// $XDG_RUNTIME_DIR is only ours already, otherwise we need a directory of our own in /tmp
+fn socket_dir() -> PathBuf {
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(format!("/tmp/nvim-cat-{}", nix::unistd::getuid())),
+    }
+}
+
+fn default_socket() -> PathBuf {
+    socket_dir().join("nvim-cat.sock")
+}
+
+// create the socket directory, making sure nobody else can get at it,
+// e.g. by having made it first in /tmp
+fn make_socket_dir() -> io::Result<()> {
+    let dir = socket_dir();
+    match DirBuilder::new().mode(0o700).create(&dir) {
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+        result => result?,
+    }
+    let meta = fs::symlink_metadata(&dir)?;
+    if !meta.is_dir() || meta.uid() != nix::unistd::getuid().as_raw() || meta.mode() & 0o077 != 0 {
+        let message = format!("{} is not a directory only you can use", dir.display());
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, message))
+    }
+    Ok(())
+}
+
+// clear out a socket left behind by a daemon that is gone,
+// but never a live one or anything that is not a socket
+fn remove_stale_socket(socket: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(socket) {
+        Ok(ref meta) if meta.file_type().is_socket() => match UnixStream::connect(socket) {
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(socket),
+            _ => Ok(()),
+        },
+        _ => Ok(()),
+    }
+}
+
+pub fn serve(socket: Option<&str>, nvim: &mut nvim::Nvim, poller: &mut poller::Poller) -> nvim::NvimResult<bool> {
+    let socket = match socket {
+        Some(socket) => PathBuf::from(socket),
+        None => {
+            make_socket_dir()?;
+            default_socket()
+        },
+    };
+    remove_stale_socket(&socket)?;
+    let listener = UnixListener::bind(&socket)?;
+
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let (filename, filetype): Header = match rmp_serde::decode::from_read(&stream) {
+            Ok(header) => header,
+            // nothing was sent, e.g. another daemon checking whether the socket is still in use
+            Err(rmp_serde::decode::Error::InvalidMarkerRead(ref e)) if e.kind() == io::ErrorKind::UnexpectedEof => continue,
+            Err(e) => {
+                print_error!("bad request: {}", e);
+                continue
+            },
+        };
+
+        nvim.set_output(Box::new(BufWriter::new(stream.try_clone()?)));
+        match ::dump_stream(&filename, stream, nvim, poller, filetype.as_ref().map(|ft| &ft[..]), None) {
+            // client went away, nothing else to do
+            Err(nvim::NvimError::IOError(ref e)) if e.kind() == io::ErrorKind::BrokenPipe => (),
+            Err(nvim::NvimError::IOError(e)) => { print_error!("{}: {}", filename, e); },
+            Err(e) => return Err(e),
+            Ok(()) => (),
+        }
+        // let go of the connection, or the client never sees the end of the output
+        nvim.set_output(Box::new(io::sink()));
+        nvim.reset()?;
+    }
+    Ok(true)
+}
+
+fn request(socket: &Path, filename: &str, mut input: Box<dyn Read + Send>, filetype: Option<&str>) -> nvim::NvimResult<()> {
+    let mut stream = UnixStream::connect(socket)?;
+    let header: Header = (filename.to_string(), filetype.map(|ft| ft.to_string()));
+    rmp_serde::encode::write(&mut stream, &header)?;
+
+    // send the input while reading the output, otherwise both ends can block on full buffers
+    let mut writer = stream.try_clone()?;
+    let sender = thread::spawn(move || -> io::Result<()> {
+        let result = io::copy(&mut input, &mut writer);
+        writer.shutdown(Shutdown::Write)?;
+        result.map(|_| ())
+    });
+
+    let stdout = io::stdout();
+    io::copy(&mut stream, &mut stdout.lock())?;
+    stdout.lock().flush()?;
+    match sender.join() {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(nvim::NvimError::SenderPanicked),
+    }
+}
+
+pub fn client(socket: Option<&str>, files: &[&str], filetype: Option<&str>) -> nvim::NvimResult<bool> {
+    let socket = socket.map_or_else(default_socket, PathBuf::from);
+    let mut success = true;
+    for &file in files {
+        let (filename, input): (&str, io::Result<Box<dyn Read + Send>>) = if file == "-" {
+            ("/dev/stdin", Ok(Box::new(io::stdin())))
+        } else {
+            (file, File::open(file).map(|f| Box::new(f) as Box<dyn Read + Send>))
+        };
+
+        let result = input.map_err(nvim::NvimError::from).and_then(|input| request(&socket, filename, input, filetype));
+        match result {
+            Err(nvim::NvimError::IOError(ref e)) if e.kind() == io::ErrorKind::BrokenPipe => break,
+            Err(nvim::NvimError::IOError(e)) => {
+                print_error!("{}: {}", file, e);
+                success = false;
+            },
+            Err(e) => {
+                print_error!("{}: {}", file, e);
+                success = false;
+                break;
+            },
+            Ok(()) => (),
+        }
+    }
+    Ok(success)
+}
@@ -0,0 +1,411 @@
+extern crate serde_bytes;
+
+use std::collections::HashSet;
+use std::fs::File;
+use nvim;
+use term;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum DiffLayout {
+    Unified,
+    // in this many columns
+    SideBySide(usize),
+}
+
+const SEPARATOR: &str = " \u{2502} ";
+const SEPARATOR_WIDTH: usize = 3;
+
+struct DiffLine {
+    text: Vec<u8>,
+    synids: Vec<usize>,
+    diff: Vec<usize>,
+    // lines of the other file shown as filler above this one
+    filler: usize,
+}
+
+struct Side {
+    lines: Vec<DiffLine>,
+    end_filler: usize,
+    // None to print tabs as they are
+    tabs: Option<term::TabStops>,
+}
+
+impl Side {
+    fn filler_above(&self, i: usize) -> usize {
+        match self.lines.get(i) {
+            Some(line) => line.filler,
+            None if i == self.lines.len() => self.end_filler,
+            None => 0,
+        }
+    }
+
+    fn changed(&self, i: Option<usize>) -> bool {
+        match i {
+            Some(i) => self.lines[i].diff.iter().any(|&id| id != 0),
+            None => true,
+        }
+    }
+}
+
+// the diff highlight ids, as nvim has them
+struct Groups {
+    add: usize,
+    change: usize,
+    delete: usize,
+    text: usize,
+    file: usize,
+    hunk: usize,
+}
+
+// one row of the aligned files, with the line from each side if it has one
+type Row = (Option<usize>, Option<usize>);
+
+// (text, synids, diff synids, filler) for each line, then the filler after the last line
+type SideValue = (Vec<(serde_bytes::ByteBuf, Vec<usize>, Vec<usize>, usize)>, usize, (usize, Vec<usize>));
+
+fn parse_side(nvim: &nvim::Nvim, (lines, end_filler, (tabstop, vartabstop)): SideValue) -> Side {
+    let lines = lines.into_iter().map(|(text, mut synids, mut diff, filler)| {
+        // one of each per byte
+        synids.resize(text.len(), 0);
+        diff.resize(text.len(), 0);
+        DiffLine{ text: text.into_vec(), synids, diff, filler }
+    }).collect();
+    Side{ lines, end_filler, tabs: nvim.buffer_tab_stops(tabstop, vartabstop) }
+}
+
+// line up the two files using the filler lines nvim puts in the diff windows
+fn align(a: &Side, b: &Side) -> Vec<Row> {
+    let mut rows = vec![];
+    let (mut i, mut j) = (0, 0);
+    // filler lines of each line are only used once
+    let (mut a_seen, mut b_seen) = (None, None);
+
+    loop {
+        if b_seen != Some(j) {
+            b_seen = Some(j);
+            // lines only in a
+            let filler = b.filler_above(j).min(a.lines.len() - i);
+            rows.extend((i..i+filler).map(|i| (Some(i), None)));
+            i += filler;
+            continue;
+        }
+        if a_seen != Some(i) {
+            a_seen = Some(i);
+            // lines only in b
+            let filler = a.filler_above(i).min(b.lines.len() - j);
+            rows.extend((j..j+filler).map(|j| (None, Some(j))));
+            j += filler;
+            continue;
+        }
+
+        let row = (Some(i).filter(|&i| i < a.lines.len()), Some(j).filter(|&j| j < b.lines.len()));
+        if row == (None, None) {
+            break
+        }
+        rows.push(row);
+        i += row.0.is_some() as usize;
+        j += row.1.is_some() as usize;
+    }
+    rows
+}
+
+// ranges of rows with changes plus @context rows either side
+fn hunks(rows: &[Row], a: &Side, b: &Side, context: usize) -> Vec<(usize, usize)> {
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for (r, &(i, j)) in rows.iter().enumerate() {
+        if !a.changed(i) && !b.changed(j) {
+            continue
+        }
+        let start = r.saturating_sub(context);
+        let end = (r + context + 1).min(rows.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    hunks
+}
+
+// @line highlighted with its syntax, with its diff highlight drawn over it.
+// nvim marks lines only in the old file as DiffAdd, but they are deletions to us
+fn line_synids(nvim: &mut nvim::Nvim, line: &DiffLine, groups: &Groups, old: bool) -> nvim::NvimResult<Vec<usize>> {
+    let mut synids = Vec::with_capacity(line.text.len());
+    for k in 0..line.text.len() {
+        let diff = line.diff.get(k).cloned().unwrap_or(0);
+        let diff = if old && diff == groups.add { groups.delete } else { diff };
+        synids.push(nvim.overlay_synid(line.synids.get(k).cloned().unwrap_or(0), diff)?);
+    }
+    Ok(synids)
+}
+
+// highlight for the space after the end of @line
+fn line_end_synid(nvim: &mut nvim::Nvim, line: &DiffLine, groups: &Groups, old: bool) -> nvim::NvimResult<usize> {
+    let diff = match line.diff.first().cloned().unwrap_or(0) {
+        id if id == groups.text => groups.change,
+        id if old && id == groups.add => groups.delete,
+        id => id,
+    };
+    nvim.overlay_synid(0, diff)
+}
+
+fn load(nvim: &mut nvim::Nvim, a: &str, b: &str) -> nvim::NvimResult<(Side, Side)> {
+    // nvim would happily edit a new file instead
+    File::open(a)?;
+    File::open(b)?;
+
+    let a: String = nvim.call("nvim_call_function", ("fnameescape", (a,)))?;
+    let b: String = nvim.call("nvim_call_function", ("fnameescape", (b,)))?;
+    nvim.try_command(&format!("edit {}", a))?;
+    let win_a = nvim.current_window()?;
+    nvim.try_command(&format!("vertical diffsplit {}", b))?;
+    let win_b = nvim.current_window()?;
+
+    let a = nvim.call("nvim_exec_lua", ("return _nvim_cat.diff(...)", (win_a,)))?;
+    let b = nvim.call("nvim_exec_lua", ("return _nvim_cat.diff(...)", (win_b,)))?;
+    Ok((parse_side(nvim, a), parse_side(nvim, b)))
+}
+
+struct Differ<'a> {
+    nvim: &'a mut nvim::Nvim,
+    names: (&'a str, &'a str),
+    a: Side,
+    b: Side,
+    groups: Groups,
+}
+
+impl<'a> Differ<'a> {
+    fn write_line(&mut self, old: bool, i: usize, prefix: &[u8]) -> nvim::NvimResult<()> {
+        let (side, prefix_synid) = if old { (&self.a, self.groups.delete) } else { (&self.b, self.groups.add) };
+        let line = &side.lines[i];
+        let synids = line_synids(self.nvim, line, &self.groups, old)?;
+        self.nvim.lineno = i;
+        self.nvim.filename = (if old { self.names.0 } else { self.names.1 }).to_string();
+        self.nvim.write_decoration(prefix, if prefix == b" " { 0 } else { prefix_synid })?;
+        // tab stops start after the prefix, as they do in the file
+        match side.tabs {
+            Some(ref tabs) => {
+                let (text, synids) = term::expand_tabs(&line.text, &synids, tabs);
+                self.nvim.write_highlighted(&text, &synids)
+            },
+            None => self.nvim.write_highlighted(&line.text, &synids),
+        }
+    }
+
+    fn unified(&mut self, rows: &[Row], hunks: &[(usize, usize)]) -> nvim::NvimResult<()> {
+        let (file, hunk) = (self.groups.file, self.groups.hunk);
+        self.nvim.write_decoration(format!("--- {}", self.names.0).as_bytes(), file)?;
+        self.nvim.end_line()?;
+        self.nvim.write_decoration(format!("+++ {}", self.names.1).as_bytes(), file)?;
+        self.nvim.end_line()?;
+
+        for &(start, end) in hunks {
+            let rows = &rows[start..end];
+            let a_lines: Vec<usize> = rows.iter().filter_map(|r| r.0).collect();
+            let b_lines: Vec<usize> = rows.iter().filter_map(|r| r.1).collect();
+            let header = format!(
+                "@@ -{},{} +{},{} @@",
+                a_lines.first().map_or(0, |i| i+1), a_lines.len(),
+                b_lines.first().map_or(0, |j| j+1), b_lines.len(),
+            );
+            self.nvim.write_decoration(header.as_bytes(), hunk)?;
+            self.nvim.end_line()?;
+
+            let mut r = 0;
+            while r < rows.len() {
+                if let (Some(i), Some(j)) = rows[r] {
+                    if !self.a.changed(Some(i)) && !self.b.changed(Some(j)) {
+                        self.nvim.write_gutter(i, 4)?;
+                        self.nvim.write_gutter(j, 4)?;
+                        self.write_line(true, i, b" ")?;
+                        self.nvim.end_line()?;
+                        r += 1;
+                        continue
+                    }
+                }
+
+                // a block of changes: all the old lines, then all the new ones
+                let block_end = r + rows[r..].iter().take_while(|&&(i, j)| self.a.changed(i) || self.b.changed(j)).count();
+                for &(i, _) in &rows[r..block_end] {
+                    if let Some(i) = i {
+                        self.nvim.write_gutter(i, 4)?;
+                        self.nvim.write_blank_gutter(4)?;
+                        self.write_line(true, i, b"-")?;
+                        self.nvim.end_line()?;
+                    }
+                }
+                for &(_, j) in &rows[r..block_end] {
+                    if let Some(j) = j {
+                        self.nvim.write_blank_gutter(4)?;
+                        self.nvim.write_gutter(j, 4)?;
+                        self.write_line(false, j, b"+")?;
+                        self.nvim.end_line()?;
+                    }
+                }
+                r = block_end;
+            }
+        }
+        Ok(())
+    }
+
+    // one half of a side by side row, expanded and cut or padded to @width columns
+    fn write_half(&mut self, old: bool, i: Option<usize>, width: usize) -> nvim::NvimResult<()> {
+        let i = match i {
+            Some(i) => i,
+            None => {
+                // filler, the way nvim draws it
+                let delete = self.groups.delete;
+                self.nvim.write_blank_gutter(4)?;
+                let filler = vec![b'-'; width];
+                return self.nvim.write_decoration(&filler, delete)
+            },
+        };
+
+        let side = if old { &self.a } else { &self.b };
+        let line = &side.lines[i];
+        let synids = line_synids(self.nvim, line, &self.groups, old)?;
+        let end_synid = line_end_synid(self.nvim, line, &self.groups, old)?;
+
+        let (mut text, mut text_synids) = match side.tabs {
+            Some(ref tabs) => term::expand_tabs(&line.text, &synids, tabs),
+            None => (line.text.clone(), synids),
+        };
+        // tabs printed as they are still have to be measured somehow
+        let tabs = side.tabs.clone().unwrap_or_default();
+        let (mut k, mut col) = (0, 0);
+        while k < text.len() {
+            let (len, w) = term::char_width(&text[k..], col, &tabs);
+            if col + w > width {
+                break
+            }
+            k += len;
+            col += w;
+        }
+        text.truncate(k);
+        text_synids.truncate(k);
+        text.extend(std::iter::repeat_n(b' ', width - col));
+        text_synids.extend(std::iter::repeat_n(end_synid, width - col));
+
+        self.nvim.write_gutter(i, 4)?;
+        self.nvim.lineno = i;
+        self.nvim.filename = (if old { self.names.0 } else { self.names.1 }).to_string();
+        self.nvim.write_highlighted(&text, &text_synids)
+    }
+
+    fn side_by_side(&mut self, rows: &[Row], hunks: &[(usize, usize)], width: usize) -> nvim::NvimResult<()> {
+        let gutter = if self.nvim.numbered() { 4 + 2 } else { 0 };
+        let half = (width.saturating_sub(SEPARATOR_WIDTH + 2*gutter) / 2).max(1);
+
+        for (h, &(start, end)) in hunks.iter().enumerate() {
+            if h > 0 {
+                let hunk = self.groups.hunk;
+                self.nvim.write_decoration("\u{22ef}".as_bytes(), hunk)?;
+                self.nvim.end_line()?;
+            }
+            for &(i, j) in &rows[start..end] {
+                self.write_half(true, i, half)?;
+                self.nvim.write_raw(SEPARATOR.as_bytes())?;
+                self.write_half(false, j, half)?;
+                self.nvim.end_line()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// show the differences between the files @a and @b using nvim's diff mode
+pub fn dump_diff(
+        a: &str,
+        b: &str,
+        nvim: &mut nvim::Nvim,
+        layout: DiffLayout,
+        context: usize,
+        ) -> nvim::NvimResult<()> {
+
+    let (side_a, side_b) = load(nvim, a, b)?;
+    let groups = Groups {
+        add: nvim.hl_id("DiffAdd")?,
+        change: nvim.hl_id("DiffChange")?,
+        delete: nvim.hl_id("DiffDelete")?,
+        text: nvim.hl_id("DiffText")?,
+        // the same as nvim's diff syntax
+        file: nvim.hl_id("Type")?,
+        hunk: nvim.hl_id("Statement")?,
+    };
+    nvim.fetch_synattrs(&[groups.file, groups.hunk, groups.add, groups.delete])?;
+    // unchanged text keeps its syntax highlight as is
+    let synids: HashSet<usize> = side_a.lines.iter().chain(&side_b.lines).flat_map(|line| line.synids.iter().cloned()).collect();
+    nvim.fetch_synattrs(&synids.into_iter().collect::<Vec<_>>())?;
+
+    let rows = align(&side_a, &side_b);
+    let mut differ = Differ{ nvim, names: (a, b), a: side_a, b: side_b, groups };
+    let hunks = hunks(&rows, &differ.a, &differ.b, context);
+
+    differ.nvim.start_output()?;
+    match layout {
+        DiffLayout::Unified => differ.unified(&rows, &hunks)?,
+        DiffLayout::SideBySide(width) => differ.side_by_side(&rows, &hunks, width)?,
+    }
+    differ.nvim.finish_output()?;
+
+    // leave a single scratch buffer in our tab for quit() to wipe,
+    // the files stay loaded in case they were already open in a --server
+    differ.nvim.nvim_command(&format!("diffoff! | only | enew | {}", nvim::SCRATCH_BUFFER))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a side with a line for each of @filler, with that many filler lines above it,
+    // and @changed lines marked as different
+    fn side(filler: &[usize], end_filler: usize, changed: &[usize]) -> Side {
+        let lines = filler.iter().enumerate().map(|(i, &filler)| DiffLine{
+            text: b"x".to_vec(),
+            synids: vec![0],
+            diff: vec![if changed.contains(&i) { 1 } else { 0 }],
+            filler,
+        }).collect();
+        Side{ lines, end_filler, tabs: None }
+    }
+
+    #[test]
+    fn align_without_changes() {
+        let rows = align(&side(&[0, 0], 0, &[]), &side(&[0, 0], 0, &[]));
+        assert_eq!(rows, vec![(Some(0), Some(0)), (Some(1), Some(1))]);
+    }
+
+    #[test]
+    fn align_filler_above_first_line() {
+        // a line only in a at the top shows as filler above b's first line
+        let rows = align(&side(&[0, 0], 0, &[0]), &side(&[1], 0, &[]));
+        assert_eq!(rows, vec![(Some(0), None), (Some(1), Some(0))]);
+    }
+
+    #[test]
+    fn align_filler_inside() {
+        // a: x y z, b: x n m z, so m is filler in a above z
+        let rows = align(&side(&[0, 0, 1], 0, &[1]), &side(&[0, 0, 0, 0], 0, &[1, 2]));
+        assert_eq!(rows, vec![
+            (Some(0), Some(0)),
+            (Some(1), Some(1)),
+            (None, Some(2)),
+            (Some(2), Some(3)),
+        ]);
+    }
+
+    #[test]
+    fn align_filler_at_end() {
+        // b has two more lines after the last one of a
+        let rows = align(&side(&[0], 2, &[]), &side(&[0, 0, 0], 0, &[1, 2]));
+        assert_eq!(rows, vec![(Some(0), Some(0)), (None, Some(1)), (None, Some(2))]);
+    }
+
+    #[test]
+    fn hunks_with_context() {
+        let (a, b) = (side(&[0; 10], 0, &[2, 4, 9]), side(&[0; 10], 0, &[2, 4, 9]));
+        let rows = align(&a, &b);
+        // the first two changes are close enough to share their context
+        assert_eq!(hunks(&rows, &a, &b, 1), vec![(1, 6), (8, 10)]);
+        assert_eq!(hunks(&rows, &a, &b, 0), vec![(2, 3), (4, 5), (9, 10)]);
+    }
+}
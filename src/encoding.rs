@@ -0,0 +1,306 @@
+use std;
+use std::io::{Read, ErrorKind};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Encoding {
+    // also used for anything we cannot decode, bytes are passed through untouched
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+    Cp1252,
+}
+
+// cp1252 characters for 0x80..=0x9f, latin1 has C1 control codes there
+const CP1252_HIGH: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8d}', 'Ž', '\u{8f}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9d}', 'ž', 'Ÿ',
+];
+
+// valid utf-8, except for a multibyte sequence cut off at the end
+fn is_utf8(bytes: &[u8]) -> bool {
+    match std::str::from_utf8(bytes) {
+        Ok(_) => true,
+        Err(e) => e.error_len().is_none(),
+    }
+}
+
+fn bom(bytes: &[u8]) -> Option<(Encoding, usize)> {
+    if bytes.starts_with(b"\xef\xbb\xbf") {
+        Some((Encoding::Utf8, 3))
+    } else if bytes.starts_with(b"\xff\xfe") {
+        Some((Encoding::Utf16Le, 2))
+    } else if bytes.starts_with(b"\xfe\xff") {
+        Some((Encoding::Utf16Be, 2))
+    } else {
+        None
+    }
+}
+
+// pick an encoding for a file starting with @sample the way nvim does with 'fileencodings':
+// the first entry that can decode it wins.
+// returns the encoding and the length of any byte order mark
+pub fn detect(sample: &[u8], fileencodings: &[String]) -> (Encoding, usize) {
+    for enc in fileencodings {
+        let found = match &enc.to_ascii_lowercase()[..] {
+            "ucs-bom" => match bom(sample) {
+                Some(found) => return found,
+                None => None,
+            },
+            "utf-8" | "utf8" | "default" if is_utf8(sample) => Some(Encoding::Utf8),
+            "utf-16" | "ucs-2" | "utf-16be" | "ucs-2be" => Some(Encoding::Utf16Be),
+            "utf-16le" | "ucs-2le" => Some(Encoding::Utf16Le),
+            "latin1" | "iso-8859-1" | "iso-8859" | "8bit-latin1" => Some(Encoding::Latin1),
+            "cp1252" | "8bit-cp1252" | "windows-1252" => Some(Encoding::Cp1252),
+            _ => None,
+        };
+        if let Some(encoding) = found {
+            return (encoding, 0)
+        }
+    }
+    (Encoding::Utf8, 0)
+}
+
+// decodes @bytes into utf-8 in @out and returns how many bytes were consumed,
+// anything left over is an incomplete character
+fn decode(encoding: Encoding, bytes: &[u8], out: &mut Vec<u8>) -> usize {
+    let mut utf8 = [0; 4];
+    match encoding {
+        Encoding::Utf8 => {
+            out.extend_from_slice(bytes);
+            bytes.len()
+        },
+        Encoding::Latin1 | Encoding::Cp1252 => {
+            for &b in bytes {
+                let c = match b {
+                    0x80..=0x9f if encoding == Encoding::Cp1252 => CP1252_HIGH[(b - 0x80) as usize],
+                    _ => b as char,
+                };
+                out.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            }
+            bytes.len()
+        },
+        Encoding::Utf16Le | Encoding::Utf16Be => {
+            let mut units: Vec<u16> = bytes.chunks(2).filter(|c| c.len() == 2).map(|c| {
+                if encoding == Encoding::Utf16Le {
+                    u16::from_le_bytes([c[0], c[1]])
+                } else {
+                    u16::from_be_bytes([c[0], c[1]])
+                }
+            }).collect();
+            // keep a high surrogate until we see the low half
+            if let Some(&last) = units.last() {
+                if (0xd800..0xdc00).contains(&last) {
+                    units.pop();
+                }
+            }
+            for c in std::char::decode_utf16(units.iter().cloned()) {
+                let c = c.unwrap_or(std::char::REPLACEMENT_CHARACTER);
+                out.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+            }
+            units.len() * 2
+        },
+    }
+}
+
+// transcodes @inner into utf-8, detecting the encoding as it is read
+pub struct Decoder<R> {
+    inner: R,
+    fileencodings: Vec<String>,
+    encoding: Option<Encoding>,
+    // utf-8 only as far as we have seen, the first invalid byte picks again
+    provisional: bool,
+    // read at most once per call, the next read could block
+    nonblocking: bool,
+    // more may be appended after eof, so keep a partial character for later
+    follow: bool,
+    raw: Vec<u8>,
+    pending: Vec<u8>,
+    decoded: Vec<u8>,
+    pos: usize,
+}
+
+impl<R> Decoder<R> where R: Read {
+    pub fn new(inner: R, fileencodings: Vec<String>) -> Self {
+        Decoder {
+            inner,
+            fileencodings,
+            encoding: None,
+            provisional: false,
+            nonblocking: false,
+            follow: false,
+            raw: vec![0; 8192],
+            pending: vec![],
+            decoded: vec![],
+            pos: 0,
+        }
+    }
+
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    pub fn set_follow(&mut self, follow: bool) {
+        self.follow = follow;
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    // decode as much of what has been read as makes whole characters
+    fn decode_pending(&mut self) {
+        let mut encoding = match self.encoding {
+            Some(encoding) => encoding,
+            None => {
+                let (encoding, bom) = detect(&self.pending, &self.fileencodings);
+                self.pending.drain(..bom);
+                self.encoding = Some(encoding);
+                self.provisional = encoding == Encoding::Utf8 && bom == 0;
+                encoding
+            },
+        };
+
+        if self.provisional {
+            match std::str::from_utf8(&self.pending) {
+                Ok(_) => (),
+                // a character cut off at the end, keep it until the rest arrives
+                Err(e) if e.error_len().is_none() => {
+                    let valid = e.valid_up_to();
+                    self.decoded.extend_from_slice(&self.pending[..valid]);
+                    self.pending.drain(..valid);
+                    return
+                },
+                // not utf-8 after all, pick again for the rest of the input
+                Err(_) => {
+                    let fileencodings: Vec<_> = self.fileencodings.iter()
+                        .filter(|enc| !enc.eq_ignore_ascii_case("ucs-bom"))
+                        .cloned()
+                        .collect();
+                    encoding = detect(&self.pending, &fileencodings).0;
+                    self.encoding = Some(encoding);
+                    self.provisional = false;
+                },
+            }
+        }
+
+        let consumed = decode(encoding, &self.pending, &mut self.decoded);
+        self.pending.drain(..consumed);
+    }
+}
+
+impl<R> Read for Decoder<R> where R: Read {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // keep reading until we can give back at least one character
+        while self.pos >= self.decoded.len() {
+            self.decoded.clear();
+            self.pos = 0;
+
+            // decoding can triple the size (cp1252 0x80 is 3 bytes in utf-8),
+            // so read little enough that it all fits in @buf
+            // and nothing is left behind when the caller stops reading
+            let want = (buf.len() / 3).clamp(4, self.raw.len());
+            let len = self.inner.read(&mut self.raw[..want])?;
+            if len == 0 {
+                if self.pending.is_empty() || self.follow {
+                    return Ok(0)
+                }
+                // eof in the middle of a character
+                self.pending.clear();
+                self.decoded.extend_from_slice("\u{fffd}".as_bytes());
+                break;
+            }
+            self.pending.extend_from_slice(&self.raw[..len]);
+            self.decode_pending();
+
+            // nothing to give back yet, but don't wait for more
+            if self.nonblocking && self.decoded.is_empty() {
+                return Err(ErrorKind::WouldBlock.into())
+            }
+        }
+
+        let len = buf.len().min(self.decoded.len() - self.pos);
+        buf[..len].copy_from_slice(&self.decoded[self.pos..self.pos+len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    // gives back one of @chunks per read, like a pipe the writer writes to in bits
+    struct Chunks(VecDeque<&'static [u8]>);
+
+    impl Read for Chunks {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let chunk = match self.0.pop_front() {
+                Some(chunk) => chunk,
+                None => return Ok(0),
+            };
+            let len = chunk.len().min(buf.len());
+            buf[..len].copy_from_slice(&chunk[..len]);
+            if len < chunk.len() {
+                self.0.push_front(&chunk[len..]);
+            }
+            Ok(len)
+        }
+    }
+
+    fn decoder(chunks: &[&'static [u8]]) -> Decoder<Chunks> {
+        let fileencodings = ["ucs-bom", "utf-8", "default", "latin1"].iter().map(|s| s.to_string()).collect();
+        Decoder::new(Chunks(chunks.iter().cloned().collect()), fileencodings)
+    }
+
+    fn decode_all(chunks: &[&'static [u8]]) -> String {
+        let mut decoded = String::new();
+        decoder(chunks).read_to_string(&mut decoded).unwrap();
+        decoded
+    }
+
+    #[test]
+    fn utf8_split_across_reads() {
+        assert_eq!(decode_all(&[b"caf\xc3", b"\xa9\n"]), "café\n");
+        assert_eq!(decode_all(&[b"\xe2", b"\x82", b"\xac\n"]), "€\n");
+    }
+
+    #[test]
+    fn latin1_after_ascii() {
+        // the ascii looked like utf-8, the é shows it was not
+        assert_eq!(decode_all(&[b"plain ascii\n", b"caf\xe9\n"]), "plain ascii\ncafé\n");
+    }
+
+    #[test]
+    fn utf16_bom() {
+        assert_eq!(decode_all(&[b"\xff\xfeh\x00i\x00\n\x00"]), "hi\n");
+        assert_eq!(decode_all(&[b"\xfe\xff\x00h\x00i"]), "hi");
+        // a surrogate pair split between reads
+        assert_eq!(decode_all(&[b"\xff\xfe\x3d\xd8", b"\x00\xde"]), "\u{1f600}");
+    }
+
+    #[test]
+    fn utf8_bom_is_dropped() {
+        assert_eq!(decode_all(&[b"\xef\xbb\xbfhi\n"]), "hi\n");
+    }
+
+    #[test]
+    fn cut_off_at_eof() {
+        assert_eq!(decode_all(&[b"caf\xc3"]), "caf\u{fffd}");
+    }
+
+    #[test]
+    fn follow_keeps_partial_character() {
+        let mut decoder = decoder(&[b"caf\xc3"]);
+        decoder.set_follow(true);
+        let mut decoded = vec![];
+        decoder.read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, b"caf");
+
+        // the rest of it is appended later
+        decoder.inner.0.push_back(b"\xa9\n");
+        decoder.read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, "café\n".as_bytes());
+    }
+}
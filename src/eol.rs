@@ -0,0 +1,76 @@
+// how a line ended in the input
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Eol {
+    Lf,
+    CrLf,
+    // old macs
+    Cr,
+    // the last line of a file without a trailing newline
+    None,
+}
+
+impl Eol {
+    pub fn as_bytes(self) -> &'static [u8] {
+        match self {
+            Eol::Lf => b"\n",
+            Eol::CrLf => b"\r\n",
+            Eol::Cr => b"\r",
+            Eol::None => b"",
+        }
+    }
+
+    // what --show-eol draws in its place
+    pub fn marker(self) -> &'static [u8] {
+        match self {
+            Eol::Lf => "␊".as_bytes(),
+            Eol::CrLf => "␍␊".as_bytes(),
+            Eol::Cr => "␍".as_bytes(),
+            Eol::None => b"",
+        }
+    }
+}
+
+// a line without its ending, and the ending
+pub type Line = (Vec<u8>, Eol);
+
+// split @line, which was read up to and including a \n, into lines pushed onto @lines.
+// with @split_cr a lone \r ends a line too.
+// whatever comes after the last line ending is returned, as it may carry on in the next read
+pub fn split_lines(mut line: Vec<u8>, split_cr: bool, lines: &mut Vec<Line>) -> Vec<u8> {
+    if split_cr {
+        let mut start = 0;
+        // a \r at the very end may yet be followed by a \n
+        for i in 0..line.len().saturating_sub(1) {
+            if line[i] == b'\r' && line[i + 1] != b'\n' {
+                lines.push((line[start..i].to_vec(), Eol::Cr));
+                start = i + 1;
+            }
+        }
+        line.drain(..start);
+    }
+
+    if line.last() != Some(&b'\n') {
+        return line
+    }
+    line.pop();
+    let eol = if line.last() == Some(&b'\r') {
+        line.pop();
+        Eol::CrLf
+    } else {
+        Eol::Lf
+    };
+    lines.push((line, eol));
+    vec![]
+}
+
+// the last line of the input from what split_lines left over at eof
+pub fn last_line(mut line: Vec<u8>, split_cr: bool) -> Option<Line> {
+    if line.is_empty() {
+        None
+    } else if split_cr && line.last() == Some(&b'\r') {
+        line.pop();
+        Some((line, Eol::Cr))
+    } else {
+        Some((line, Eol::None))
+    }
+}
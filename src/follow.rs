@@ -0,0 +1,135 @@
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use nix;
+use nix::sys::inotify::{Inotify, InitFlags, AddWatchFlags};
+use nix::poll::{poll, PollFd, PollFlags};
+
+fn nix_to_io(e: nix::Error) -> io::Error {
+    match e.as_errno() {
+        Some(errno) => io::Error::from_raw_os_error(errno as i32),
+        None => io::Error::other(e),
+    }
+}
+
+// reads a file like tail -F: at eof it checks whether the file was
+// truncated (so starts again from the top) or replaced (so opens the new one)
+pub struct Follower {
+    path: PathBuf,
+    file: File,
+    pos: u64,
+}
+
+impl Follower {
+    pub fn new(path: &str) -> io::Result<Self> {
+        Ok(Follower{ path: PathBuf::from(path), file: File::open(path)?, pos: 0 })
+    }
+}
+
+impl Read for Follower {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut len = self.file.read(buf)?;
+        if len == 0 {
+            match fs::metadata(&self.path) {
+                // rotated
+                Ok(ref meta) if meta.ino() != self.file.metadata()?.ino() || meta.dev() != self.file.metadata()?.dev() => {
+                    self.file = File::open(&self.path)?;
+                    self.pos = 0;
+                },
+                Ok(ref meta) if meta.len() < self.pos => {
+                    self.file.seek(SeekFrom::Start(0))?;
+                    self.pos = 0;
+                },
+                // not changed, or deleted and not yet replaced
+                _ => return Ok(0),
+            }
+            len = self.file.read(buf)?;
+        }
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl AsRawFd for Follower {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+// wakes up whenever the followed file may have changed
+pub struct Watcher {
+    inotify: Inotify,
+    name: Option<OsString>,
+}
+
+impl Watcher {
+    pub fn new(path: &str) -> io::Result<Self> {
+        let path = Path::new(path);
+        // watch the directory so that we hear about the file being replaced too
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC).map_err(nix_to_io)?;
+        let flags = AddWatchFlags::IN_MODIFY | AddWatchFlags::IN_ATTRIB | AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO;
+        if let Err(e) = inotify.add_watch(dir, flags) {
+            nix::unistd::close(inotify.as_raw_fd()).ok();
+            return Err(nix_to_io(e))
+        }
+        Ok(Watcher{ inotify, name: path.file_name().map(OsString::from) })
+    }
+
+    // read all pending events, returns whether any were about our file
+    pub fn changed(&mut self) -> io::Result<bool> {
+        let mut changed = false;
+        loop {
+            match self.inotify.read_events() {
+                Ok(events) => changed |= events.iter().any(|e| e.name == self.name),
+                Err(nix::Error::Sys(nix::errno::Errno::EAGAIN)) => return Ok(changed),
+                Err(e) => return Err(nix_to_io(e)),
+            }
+        }
+    }
+}
+
+impl AsRawFd for Watcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        nix::unistd::close(self.inotify.as_raw_fd()).ok();
+    }
+}
+
+// a Follower that never reaches eof, it waits for more to be appended instead
+pub struct Tail {
+    follower: Follower,
+    watcher: Watcher,
+}
+
+impl Tail {
+    pub fn new(path: &str) -> io::Result<Self> {
+        Ok(Tail{ follower: Follower::new(path)?, watcher: Watcher::new(path)? })
+    }
+}
+
+impl Read for Tail {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let len = self.follower.read(buf)?;
+            if len > 0 || buf.is_empty() {
+                return Ok(len)
+            }
+            let mut fds = [PollFd::new(self.watcher.as_raw_fd(), PollFlags::POLLIN)];
+            poll(&mut fds, -1).map_err(nix_to_io)?;
+            self.watcher.changed()?;
+        }
+    }
+}
@@ -0,0 +1,302 @@
+-- Collects the highlight group of every byte of a line from legacy syntax,
+-- tree-sitter and extmarks (lsp semantic tokens, plugins etc),
+-- resolving overlaps by priority the same way the nvim ui does.
+
+local M = {}
+
+-- legacy syntax is drawn underneath everything else
+local SYNTAX_PRIORITY = 0
+local TREESITTER_PRIORITY = (vim.highlight and vim.highlight.priorities or {}).treesitter or 100
+local EXTMARK_PRIORITY = 4096
+
+local hl_cache = {}
+local query_cache = {}
+-- vim pattern set by --search, and compiled
+local search = nil
+local search_regex = nil
+
+local function hl_defined(name)
+    if vim.api.nvim_get_hl then
+        return next(vim.api.nvim_get_hl(0, {name = name, link = true})) ~= nil
+    end
+    return vim.fn.hlexists(name) == 1
+end
+
+-- id of the most specific defined group for @capture, e.g. @string.escape.lua -> @string.escape -> @string
+local function capture_hl_id(capture, lang)
+    local key = capture .. '.' .. lang
+    local id = hl_cache[key]
+    if id then
+        return id
+    end
+
+    id = 0
+    local name = '@' .. key
+    while name do
+        if hl_defined(name) then
+            id = vim.fn.hlID(name)
+            break
+        end
+        name = name:match('^(.+)%.[^.]*$')
+    end
+    hl_cache[key] = id
+    return id
+end
+
+local function get_query(lang)
+    if query_cache[lang] == nil then
+        local get = vim.treesitter.query.get or vim.treesitter.query.get_query
+        local ok, query = pcall(get, lang, 'highlights')
+        query_cache[lang] = ok and query or false
+    end
+    return query_cache[lang]
+end
+
+local function apply(ids, priorities, start, finish, id, priority)
+    for col = start + 1, finish do
+        if priority >= priorities[col] then
+            ids[col] = id
+            priorities[col] = priority
+        end
+    end
+end
+
+-- start and end byte of a range on @row, or nil if it does not touch @row
+local function clip(row, len, start_row, start_col, end_row, end_col)
+    if start_row > row or end_row < row then
+        return nil
+    end
+    local start = start_row < row and 0 or start_col
+    local finish = end_row > row and len or math.min(end_col, len)
+    if finish <= start then
+        return nil
+    end
+    return start, finish
+end
+
+local function add_syntax(ids, priorities, lnum, len)
+    if vim.bo.syntax == '' then
+        return
+    end
+    for col = 1, len do
+        local id = vim.fn.synID(lnum, col, 0)
+        if id ~= 0 then
+            ids[col] = id
+            priorities[col] = SYNTAX_PRIORITY
+        end
+    end
+end
+
+local function add_treesitter(ids, priorities, buf, row, len)
+    local highlighter = vim.treesitter.highlighter and vim.treesitter.highlighter.active[buf]
+    if not highlighter then
+        return
+    end
+
+    -- the ui normally parses on redraw, but we never redraw
+    pcall(highlighter.tree.parse, highlighter.tree, true)
+    highlighter.tree:for_each_tree(function(tstree, tree)
+        local lang = tree:lang()
+        local query = get_query(lang)
+        if not query or not tstree then
+            return
+        end
+
+        for capture, node, metadata in query:iter_captures(tstree:root(), buf, row, row + 1) do
+            local name = query.captures[capture]
+            -- captures starting with _ are private to the query
+            if name:sub(1, 1) ~= '_' then
+                local start, finish = clip(row, len, node:range())
+                local id = capture_hl_id(name, lang)
+                if start and id ~= 0 then
+                    local priority = metadata.priority or (metadata[capture] or {}).priority
+                    apply(ids, priorities, start, finish, id, tonumber(priority) or TREESITTER_PRIORITY)
+                end
+            end
+        end
+    end)
+end
+
+local function add_extmarks(ids, priorities, buf, row, len)
+    local ok, extmarks = pcall(vim.api.nvim_buf_get_extmarks, buf, -1, {row, 0}, {row, -1}, {details = true, overlap = true})
+    if not ok then
+        -- no overlap option before nvim 0.10
+        ok, extmarks = pcall(vim.api.nvim_buf_get_extmarks, buf, -1, {row, 0}, {row, -1}, {details = true})
+    end
+    if not ok then
+        -- nor all namespaces at once in the oldest we support
+        return
+    end
+
+    -- lower priorities first so that higher ones are drawn over them
+    table.sort(extmarks, function(a, b)
+        return (a[4].priority or EXTMARK_PRIORITY) < (b[4].priority or EXTMARK_PRIORITY)
+    end)
+
+    for _, extmark in ipairs(extmarks) do
+        local details = extmark[4]
+        local group = details.hl_group
+        if type(group) == 'table' then
+            group = group[#group]
+        end
+        if group and details.end_row then
+            local start, finish = clip(row, len, extmark[2], extmark[3], details.end_row, details.end_col)
+            local id = type(group) == 'number' and group or vim.fn.hlID(group)
+            if start and id ~= 0 then
+                apply(ids, priorities, start, finish, id, details.priority or EXTMARK_PRIORITY)
+            end
+        end
+    end
+end
+
+-- highlight group id of each byte of line @lnum (1-based) which has length @len
+function M.line(lnum, len)
+    local buf = vim.api.nvim_get_current_buf()
+    local ids = {}
+    local priorities = {}
+    for col = 1, len do
+        ids[col] = 0
+        priorities[col] = -1
+    end
+
+    add_syntax(ids, priorities, lnum, len)
+    add_treesitter(ids, priorities, buf, lnum - 1, len)
+    add_extmarks(ids, priorities, buf, lnum - 1, len)
+    return ids
+end
+
+-- byte ranges {start, end} of matches of the search pattern on @row.
+-- the pattern is matched against the whole line, not what is left of it after the last match,
+-- so ^, \< and \%Nc mean what they do in vim
+local function search_matches(buf, row, len)
+    local matches = {}
+    if not search then
+        return matches
+    end
+    local lnum = row + 1
+
+    if vim.fn.exists('*matchbufline') == 1 then
+        for _, match in ipairs(vim.fn.matchbufline(buf, search, lnum, lnum)) do
+            if #match.text > 0 then
+                table.insert(matches, {match.byteidx, match.byteidx + #match.text})
+            end
+        end
+        return matches
+    end
+
+    -- older nvim: find where each match starts by moving the cursor along the line,
+    -- then how long it is with the regex from there
+    local col = 0
+    while col < len do
+        vim.api.nvim_win_set_cursor(0, {lnum, col})
+        local pos = vim.fn.searchpos(search, 'cnW', lnum)
+        if pos[1] ~= lnum then
+            break
+        end
+        local start = pos[2] - 1
+        local from, to = search_regex:match_line(buf, row, start)
+        if from == 0 and to > 0 then
+            table.insert(matches, {start, start + to})
+            col = start + to
+        else
+            -- empty match, try again one byte on
+            col = start + 1
+        end
+    end
+    return matches
+end
+
+function M.set_search(pattern)
+    search_regex = vim.regex(pattern)
+    search = pattern
+end
+
+-- {'tabstop', 'vartabstop' as a list} of the current buffer
+function M.tabstops()
+    local vartabstop = {}
+    for n in (vim.bo.vartabstop or ''):gmatch('%d+') do
+        table.insert(vartabstop, tonumber(n))
+    end
+    return {vim.bo.tabstop, vartabstop}
+end
+
+-- runs of equal @ids as {start, end, id}, 0-based with exclusive ends, leaving out 0
+local function spans(ids)
+    local result = {}
+    local start = 1
+    for col = 2, #ids + 1 do
+        if ids[col] ~= ids[start] then
+            if ids[start] ~= 0 then
+                table.insert(result, {start - 1, col - 1, ids[start]})
+            end
+            start = col
+        end
+    end
+    return result
+end
+
+-- highlight spans of every line in the current buffer, and M.tabstops()
+function M.buffer()
+    local result = {}
+    for lnum, line in ipairs(vim.api.nvim_buf_get_lines(0, 0, -1, false)) do
+        result[lnum] = spans(M.line(lnum, #line))
+    end
+    return {result, M.tabstops()}
+end
+
+-- insert @lines before @row (0-based) and highlight them all in one go:
+-- {{highlight spans, search matches} for each line, M.tabstops()}.
+-- lines not in @wanted are only there for the syntax of later ones and get nothing.
+-- with @detect the filetype is detected first if it is not known yet
+function M.chunk(row, lines, wanted, detect)
+    local buf = vim.api.nvim_get_current_buf()
+    vim.api.nvim_buf_set_lines(buf, row, row, true, lines)
+    if detect then
+        local err = M.try("if &ft == '' | filetype detect | endif")
+        if err then
+            vim.notify(err, vim.log.levels.ERROR)
+        end
+    end
+
+    local result = {}
+    for i, line in ipairs(lines) do
+        local lnum = row + i
+        if wanted[i] then
+            result[i] = {spans(M.line(lnum, #line)), search_matches(buf, lnum - 1, #line)}
+        else
+            result[i] = {{}, {}}
+        end
+    end
+    return {result, M.tabstops()}
+end
+
+-- everything needed to render the buffer in diff window @win:
+-- {text, highlight ids, diff highlight ids, filler lines above} for each line,
+-- plus the number of filler lines after the last line and M.tabstops()
+function M.diff(win)
+    return vim.api.nvim_win_call(win, function()
+        local lines = vim.api.nvim_buf_get_lines(0, 0, -1, false)
+        local result = {}
+        for lnum, line in ipairs(lines) do
+            local len = #line
+            -- an empty line still has a diff highlight
+            local diff = {vim.fn.diff_hlID(lnum, 1)}
+            for col = 2, len do
+                diff[col] = vim.fn.diff_hlID(lnum, col)
+            end
+            result[lnum] = {line, M.line(lnum, len), diff, vim.fn.diff_filler(lnum)}
+        end
+        return {result, vim.fn.diff_filler(#lines + 1), M.tabstops()}
+    end)
+end
+
+-- run the ex command @cmd, returning any error (e.g. from an ftplugin)
+-- instead of failing the request
+function M.try(cmd)
+    local ok, err = pcall(vim.cmd, cmd)
+    if not ok then
+        return err
+    end
+end
+
+_nvim_cat = M
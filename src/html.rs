@@ -0,0 +1,74 @@
+use std::fmt::Write;
+use synattr::{SynAttr, Colour};
+
+pub const PRE_START: &[u8] = b"<pre class=\"nvim-cat\">";
+pub const PRE_END: &[u8] = b"</pre>\n";
+
+fn css_colour(colour: Colour) -> String {
+    let (r, g, b) = colour.rgb();
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+// css class for the highlight group @name
+pub fn push_class(vec: &mut Vec<u8>, name: &str) {
+    vec.extend_from_slice(b"hl-");
+    for c in name.bytes() {
+        vec.push(if c.is_ascii_alphanumeric() || c == b'_' || c == b'-' { c } else { b'-' });
+    }
+}
+
+pub fn push_span_start(vec: &mut Vec<u8>, attr: &SynAttr) {
+    vec.extend_from_slice(b"<span class=\"");
+    push_class(vec, &attr.name);
+    vec.extend_from_slice(b"\">");
+}
+
+pub fn push_escaped(vec: &mut Vec<u8>, bytes: &[u8]) {
+    for &c in bytes {
+        match c {
+            b'<' => vec.extend_from_slice(b"&lt;"),
+            b'>' => vec.extend_from_slice(b"&gt;"),
+            b'&' => vec.extend_from_slice(b"&amp;"),
+            b'"' => vec.extend_from_slice(b"&quot;"),
+            _ => vec.push(c),
+        }
+    }
+}
+
+fn push_rule(css: &mut String, selector: &str, attr: &SynAttr) {
+    let (mut fg, mut bg) = (attr.fg_colour, attr.bg_colour);
+    if attr.is_reverse() {
+        std::mem::swap(&mut fg, &mut bg);
+    }
+
+    write!(css, "{} {{", selector).unwrap();
+    if let Some(fg) = fg {
+        write!(css, " color: {};", css_colour(fg)).unwrap();
+    }
+    if let Some(bg) = bg {
+        write!(css, " background-color: {};", css_colour(bg)).unwrap();
+    }
+    if attr.is_bold() {
+        css.push_str(" font-weight: bold;");
+    }
+    if attr.is_italic() {
+        css.push_str(" font-style: italic;");
+    }
+    if attr.is_underline() {
+        css.push_str(" text-decoration: underline;");
+    }
+    css.push_str(" }\n");
+}
+
+// stylesheet with the Normal group on the <pre> and a class for each of @attrs
+pub fn stylesheet<'a, I: Iterator<Item=&'a SynAttr>>(normal: &SynAttr, attrs: I) -> String {
+    let mut css = String::from("<style>\n");
+    push_rule(&mut css, "pre.nvim-cat", normal);
+    for attr in attrs {
+        let mut class = vec![];
+        push_class(&mut class, &attr.name);
+        push_rule(&mut css, &format!("pre.nvim-cat .{}", String::from_utf8(class).unwrap()), attr);
+    }
+    css.push_str("</style>\n");
+    css
+}
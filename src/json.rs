@@ -0,0 +1,44 @@
+use std::io::Write;
+use synattr::{SynAttr, Colour};
+
+pub fn push_string(vec: &mut Vec<u8>, bytes: &[u8]) {
+    vec.push(b'"');
+    for &c in String::from_utf8_lossy(bytes).as_bytes() {
+        match c {
+            b'"' => vec.extend_from_slice(b"\\\""),
+            b'\\' => vec.extend_from_slice(b"\\\\"),
+            b'\n' => vec.extend_from_slice(b"\\n"),
+            b'\t' => vec.extend_from_slice(b"\\t"),
+            0..=0x1f | 0x7f => write!(vec, "\\u{:04x}", c).unwrap(),
+            _ => vec.push(c),
+        }
+    }
+    vec.push(b'"');
+}
+
+fn push_colour(vec: &mut Vec<u8>, colour: Option<Colour>) {
+    match colour.map(Colour::rgb) {
+        Some((r, g, b)) => write!(vec, "\"#{:02x}{:02x}{:02x}\"", r, g, b).unwrap(),
+        None => vec.extend_from_slice(b"null"),
+    }
+}
+
+// one record for the run of bytes @start..@end on (1-based) @lineno highlighted with @attr
+pub fn push_token(vec: &mut Vec<u8>, file: &str, lineno: usize, start: usize, end: usize, attr: &SynAttr) {
+    vec.extend_from_slice(b"{\"file\":");
+    push_string(vec, file.as_bytes());
+    write!(vec, ",\"line\":{},\"start\":{},\"end\":{},\"group\":", lineno, start, end).unwrap();
+    push_string(vec, attr.name.as_bytes());
+    vec.extend_from_slice(b",\"fg\":");
+    push_colour(vec, attr.fg_colour);
+    vec.extend_from_slice(b",\"bg\":");
+    push_colour(vec, attr.bg_colour);
+    writeln!(
+        vec,
+        ",\"bold\":{},\"reverse\":{},\"italic\":{},\"underline\":{}}}",
+        attr.is_bold(),
+        attr.is_reverse(),
+        attr.is_italic(),
+        attr.is_underline(),
+    ).unwrap();
+}
@@ -8,7 +8,7 @@ extern crate libc;
 extern crate clap;
 
 use std::fs::File;
-use std::io::ErrorKind;
+use std::io::{Read, ErrorKind};
 use std::os::unix::io::AsRawFd;
 use clap::{Arg, App};
 
@@ -23,34 +23,119 @@ macro_rules! print_error(
     })
 );
 
+macro_rules! print_warning(
+    ($fmt:expr) => ({
+        use ::std::io::Write;
+        writeln!(::std::io::stderr(), concat!("WARNING: ", $fmt)).ok()
+    });
+    ($fmt:expr, $($arg:tt)*) => ({
+        use ::std::io::Write;
+        writeln!(::std::io::stderr(), concat!("WARNING: ", $fmt), $($arg)*).ok()
+    })
+);
+
 mod rpc;
 mod nvim;
 mod epoll;
 mod synattr;
 mod poller;
 mod color;
+mod html;
+mod json;
+mod daemon;
+mod encoding;
+mod plain;
+mod term;
+mod diff;
+mod patch;
+mod ranges;
+mod pager;
+mod follow;
+mod eol;
 
 fn dump_file(
         filename: &str,
         nvim: &mut nvim::Nvim,
         poller: &mut poller::Poller,
         filetype: Option<&str>,
+        patch: bool,
+        follow: bool,
+        ) -> nvim::NvimResult<()> {
+
+    // a pipe can't grow again once it has ended
+    if follow && filename != "-" && !patch {
+        let file = follow::Follower::new(filename)?;
+        let mut watcher = follow::Watcher::new(filename)?;
+        poller.add_watch(watcher.as_raw_fd())?;
+        let result = dump_stream(filename, file, nvim, poller, filetype, Some(&mut watcher));
+        // the watcher is still open here, and how the file went matters more than the clean up
+        return result.and(poller.rm_watch())
+    }
+
+    let stdin = filename == "-";
+    let filename = if stdin { "/dev/stdin" } else { filename };
+    let file = File::open(filename)?;
+    if patch || (filetype.is_none() && patch::is_patch_name(filename)) {
+        return patch::dump_patch(filename, file, nvim)
+    }
+    if stdin && filetype.is_none() {
+        // with no name to go by, a patch can only be told by how it starts
+        let input = poller::Peeked::first_line(file)?;
+        if patch::is_patch_start(input.head()) {
+            return patch::dump_patch(filename, input, nvim)
+        }
+        // the poller never saw the line that was read ahead
+        poller.wake_stdin();
+        return dump_stream(filename, input, nvim, poller, filetype, None)
+    }
+    dump_stream(filename, file, nvim, poller, filetype, None)
+}
+
+// highlight everything read from @input as if it were the file @file
+fn dump_stream<R: Read + AsRawFd>(
+        file: &str,
+        input: R,
+        nvim: &mut nvim::Nvim,
+        poller: &mut poller::Poller,
+        filetype: Option<&str>,
+        watcher: Option<&mut follow::Watcher>,
         ) -> nvim::NvimResult<()> {
 
-    let file = if filename == "-" { "/dev/stdin" } else { filename };
     nvim.buf_set_name(file)?;
     // println!("{}", file);
 
     if let Some(filetype) = filetype {
-        nvim.nvim_command(&format!("set ft={}", filetype))?;
+        nvim.try_command(&format!("set ft={}", filetype))?;
     } else {
-        nvim.nvim_command(&format!("set ft= | doautocmd BufRead {}", file))?;
+        nvim.try_command(&format!("set ft= | doautocmd BufRead {}", file))?;
     }
-    nvim.press_enter()?; // press enter now and then to get past blocking error messages
 
-    let file = File::open(file)?;
-    poller.add_stdin(file.as_raw_fd())?;
-    let mut file = poller::NBBufReader::new(file);
+    poller.add_stdin(input.as_raw_fd())?;
+    let mut file = poller::NBBufReader::new(input, nvim.fileencodings());
+    file.set_follow(watcher.is_some());
+    file.set_split_cr(nvim.split_cr());
+    let result = read_stream(&mut file, nvim, poller, filetype, watcher);
+    // make sure an abandoned stream is not left in the poller, while it is still open
+    result.and(poller.rm_stdin())
+}
+
+// lines read but not yet printed before we stop reading and wait for nvim to catch up
+const MAX_BACKLOG: usize = 10000;
+// most lines sent to nvim to be highlighted at once
+const CHUNK_LINES: usize = 1000;
+
+// with a @watcher, carries on reading whatever is appended to the file after eof
+fn read_stream<R: Read + AsRawFd>(
+        file: &mut poller::NBBufReader<R>,
+        nvim: &mut nvim::Nvim,
+        poller: &mut poller::Poller,
+        filetype: Option<&str>,
+        mut watcher: Option<&mut follow::Watcher>,
+        ) -> nvim::NvimResult<()> {
+
+    nvim.start_output()?;
+    // a line waiting for the context after it must not stop that context being read
+    let max_backlog = MAX_BACKLOG.max(nvim.lines_ahead() + CHUNK_LINES);
 
     let mut lineno = 0;
     loop {
@@ -58,31 +143,45 @@ fn dump_file(
             poller::PollResult::Stdout => {
                 nvim.process_event()?;
             },
+            // don't read faster than nvim can highlight
+            poller::PollResult::Stdin if lineno - nvim.lineno >= max_backlog => {
+                nvim.process_event()?;
+            },
             poller::PollResult::Stdin => {
-                nvim.press_enter()?; // press enter now and then to get past blocking error messages
                 match file.read_lines()? {
-                    Some(lines) => {
-                        for line in lines {
-                            nvim.add_line(line, lineno)?;
+                    Some(mut lines) => {
+                        let wanted = (0..lines.len()).take_while(|&i| nvim.wants_line_from(lineno + i)).count();
+                        lines.truncate(wanted);
+                        while !lines.is_empty() {
+                            let chunk: Vec<_> = lines.drain(..lines.len().min(CHUNK_LINES)).collect();
+                            let len = chunk.len();
                             // run filetype detect over first 10 lines
-                            if filetype.is_none() && lineno < 10 {
-                                nvim.filetype_detect()?;
-                            }
-                            lineno += 1;
+                            nvim.add_lines(chunk, lineno, filetype.is_none() && lineno < 10)?;
+                            lineno += len;
+                        }
+                        // the rest of the input can't change anything we print
+                        if !nvim.wants_line_from(lineno) {
+                            break
                         }
                     },
-                    None => {
-                        poller.rm_stdin()?;
-                        break;
-                    }
+                    // wait until the file changes
+                    None if watcher.is_some() => poller.rm_stdin()?,
+                    None => break,
+                }
+            },
+            poller::PollResult::Watch => {
+                if watcher.as_mut().unwrap().changed()? {
+                    poller.add_stdin(file.get_ref().as_raw_fd())?;
                 }
             },
         }
     }
 
+    nvim.end_input(lineno)?;
     while nvim.lineno < lineno {
         nvim.process_event()?;
     }
+    nvim.finish_output()?;
     Ok(())
 }
 
@@ -104,40 +203,408 @@ fn entrypoint() -> nvim::NvimResult<bool> {
              .short("n")
              .long("number")
              .help("Number output lines"))
+        .arg(Arg::with_name("number_nonblank")
+             .short("b")
+             .long("number-nonblank")
+             .help("Number nonempty output lines, overrides -n"))
+        .arg(Arg::with_name("show_all")
+             .short("A")
+             .long("show-all")
+             .help("Equivalent to -vET"))
+        .arg(Arg::with_name("e")
+             .short("e")
+             .help("Equivalent to -vE"))
+        .arg(Arg::with_name("show_ends")
+             .short("E")
+             .long("show-ends")
+             .help("Display $ at the end of each line"))
+        .arg(Arg::with_name("squeeze_blank")
+             .short("s")
+             .long("squeeze-blank")
+             .help("Suppress repeated empty output lines"))
+        .arg(Arg::with_name("t")
+             .short("t")
+             .help("Equivalent to -vT"))
+        .arg(Arg::with_name("show_tabs")
+             .short("T")
+             .long("show-tabs")
+             .help("Display TAB characters as ^I"))
+        .arg(Arg::with_name("show_nonprinting")
+             .short("v")
+             .long("show-nonprinting")
+             .help("Use ^ and M- notation, except for LFD and TAB"))
+        .arg(Arg::with_name("preserve_eol")
+             .long("preserve-eol")
+             .help("Keep each line ending (LF, CRLF or CR) as it was, and leave out the final newline \
+                    if the input has none"))
+        .arg(Arg::with_name("show_eol")
+             .long("show-eol")
+             .help("Mark where each line ends and how, with ␊ for LF, ␍␊ for CRLF and ␍ for CR"))
         .arg(Arg::with_name("restricted_mode")
              .short("Z")
              .help("Restricted mode"))
+        .arg(Arg::with_name("verbose")
+             .long("verbose")
+             .help("Print nvim's messages, e.g. errors in your config or colorscheme, to stderr"))
+        .arg(Arg::with_name("strict")
+             .long("strict")
+             .help("Fail on the first error message from nvim instead of carrying on"))
         .arg(Arg::with_name("colorscheme")
             .value_name("colorscheme")
-            .short("s")
+            .long("colorscheme")
             .help("Colorscheme"))
+        .arg(Arg::with_name("output_format")
+            .long("output-format")
+            .value_name("format")
+            .possible_values(&["ansi", "html", "json"])
+            .default_value("ansi")
+            .help("Emit ANSI escapes, HTML with a class per highlight group, \
+                   or one JSON record per highlighted run"))
+        .arg(Arg::with_name("color")
+            .long("color")
+            .value_name("when")
+            .possible_values(&["auto", "always", "never"])
+            .default_value("auto")
+            .help("Highlight the output; auto means only if stdout is a terminal and $NO_COLOR is not set. \
+                   Without highlighting, files are copied as is like cat"))
+        .arg(Arg::with_name("colors")
+            .long("colors")
+            .value_name("mode")
+            .possible_values(&["truecolor", "256", "16", "8"])
+            .help("Number of colours the terminal supports [default: from $COLORTERM or terminfo]"))
+        .arg(Arg::with_name("server")
+            .long("server")
+            .value_name("address")
+            // its messages go to its own ui
+            .conflicts_with_all(&["vimrc", "colorscheme", "restricted_mode", "verbose", "strict"])
+            .help("Use the nvim listening on <address> (a socket path or host:port) instead of starting one"))
+        .arg(Arg::with_name("daemon")
+            .long("daemon")
+            .conflicts_with("client")
+            .help("Keep nvim running and highlight files sent by --client"))
+        .arg(Arg::with_name("client")
+            .long("client")
+            // a request only carries the file name and filetype, everything else is up to the daemon
+            .conflicts_with_all(&[
+                "vimrc", "colorscheme", "restricted_mode", "verbose", "strict", "server",
+                "numbered", "number_nonblank", "show_all", "e", "show_ends", "squeeze_blank", "t", "show_tabs",
+                "show_nonprinting", "preserve_eol", "show_eol", "output_format", "colors",
+                "search", "only_matching_lines", "line_range", "center", "highlight_line", "highlight_group",
+                "wrap", "terminal_width", "tabs", "max_buffer_lines",
+            ])
+            .help("Send files to a running --daemon instead of starting nvim"))
+        .arg(Arg::with_name("socket")
+            .long("socket")
+            .value_name("path")
+            .help("Unix socket for --daemon and --client [default: $XDG_RUNTIME_DIR/nvim-cat.sock, \
+                   or /tmp/nvim-cat-<uid>/nvim-cat.sock]"))
+        .arg(Arg::with_name("diff")
+            .long("diff")
+            .conflicts_with_all(&["daemon", "client"])
+            .requires("FILE")
+            .help("Show the differences between the two FILEs, highlighted with nvim's diff mode"))
+        .arg(Arg::with_name("diff_layout")
+            .long("diff-layout")
+            .value_name("layout")
+            .possible_values(&["unified", "side-by-side"])
+            .default_value("unified")
+            .help("Layout of --diff output"))
+        .arg(Arg::with_name("context")
+            .long("context")
+            .short("C")
+            .value_name("lines")
+            .default_value("3")
+            .validator(|v| v.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+            .help("Number of lines shown around each change with --diff, or each match with --only-matching-lines"))
+        .arg(Arg::with_name("search")
+            .long("search")
+            .value_name("pattern")
+            .help("Highlight matches of the vim regex <pattern> with the Search group"))
+        .arg(Arg::with_name("only_matching_lines")
+            .long("only-matching-lines")
+            .requires("search")
+            .help("Only print lines matching --search and the --context lines around them, like grep -C"))
+        .arg(Arg::with_name("patch")
+            .long("patch")
+            .conflicts_with("filetype")
+            .help("Treat the input as a unified diff and highlight each file in it with its own syntax \
+                   (the default for *.patch and *.diff), e.g. git log -p | nvim-cat --patch"))
+        .arg(Arg::with_name("line_range")
+            .long("line-range")
+            .short("r")
+            .value_name("N:M")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|v| ranges::parse(&v).map(|_| ()))
+            .help("Only print lines N to M (N:, :M and N also work); can be given more than once. \
+                   Earlier lines are still read for the syntax highlighting"))
+        .arg(Arg::with_name("center")
+            .long("center")
+            .value_name("line")
+            .requires("height")
+            .conflicts_with("line_range")
+            .validator(|v| v.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+            .help("Only print the --height lines around <line>, e.g. for a preview window"))
+        .arg(Arg::with_name("height")
+            .long("height")
+            .value_name("lines")
+            .requires("center")
+            .validator(|v| v.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+            .help("Number of lines to print with --center"))
+        .arg(Arg::with_name("highlight_line")
+            .long("highlight-line")
+            .short("H")
+            .value_name("N:M")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|v| ranges::parse(&v).map(|_| ()))
+            .help("Draw --highlight-group over lines N to M (or just line N); can be given more than once"))
+        .arg(Arg::with_name("highlight_group")
+            .long("highlight-group")
+            .value_name("group")
+            .default_value("CursorLine")
+            .help("Highlight group for --highlight-line"))
+        .arg(Arg::with_name("wrap")
+            .long("wrap")
+            .value_name("mode")
+            .possible_values(&["auto", "never", "character", "word"])
+            .default_value("auto")
+            .help("Wrap long lines to the terminal width; auto wraps at any character if stdout is a terminal"))
+        .arg(Arg::with_name("terminal_width")
+            .long("terminal-width")
+            .value_name("width")
+            .validator(|v| v.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+            .help("Wrap at <width> columns instead of the width of the terminal"))
+        .arg(Arg::with_name("tabs")
+            .long("tabs")
+            .value_name("width")
+            .validator(|v| v.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+            .help("Expand tabs to <width> columns, or 0 to print them as is [default: the file's 'tabstop' and 'vartabstop' in nvim]"))
+        .arg(Arg::with_name("paging")
+            .long("paging")
+            .value_name("when")
+            .possible_values(&["auto", "always", "never"])
+            .default_value("auto")
+            .help("Send the output through $NVIM_CAT_PAGER or $PAGER (less -RF by default); \
+                   auto means only if stdout is a terminal"))
+        .arg(Arg::with_name("max_buffer_lines")
+            .long("max-buffer-lines")
+            .value_name("lines")
+            .default_value("10000")
+            .validator(|v| v.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+            .help("Drop printed lines from nvim beyond the last <lines>, which are kept for the syntax of what follows, \
+                   so huge files and endless streams don't use ever more memory; 0 keeps everything"))
+        .arg(Arg::with_name("follow")
+            .short("F")
+            .long("follow")
+            .conflicts_with_all(&["diff", "patch", "daemon", "client"])
+            .help("Keep printing lines as they are appended to a single FILE, like tail -F, \
+                   following it if it is truncated or replaced"))
         .arg(Arg::with_name("FILE")
              .multiple(true))
         .get_matches();
 
-    let filetype = matches.value_of("filetype");
-    let vimrc = matches.value_of("vimrc");
-    let colorscheme = matches.value_of("colorscheme");
     let files: Vec<&str> = match matches.values_of("FILE") {
         Some(values) => values.collect(),
         None => vec!["-"],
     };
 
+    // other formats are meant for files and pipes, so --color only affects ansi output.
+    // a daemon's stdout is not where its output goes, so leave it to the client
+    let color = match matches.value_of("color") {
+        _ if matches.is_present("daemon") => true,
+        Some("always") => true,
+        Some("never") => false,
+        _ => {
+            let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+            !no_color && nix::unistd::isatty(libc::STDOUT_FILENO).unwrap_or(false)
+        },
+    };
+    let format = match matches.value_of("output_format") {
+        Some("html") => nvim::OutputFormat::Html,
+        Some("json") => nvim::OutputFormat::Json,
+        _ if !color => nvim::OutputFormat::Plain,
+        _ => nvim::OutputFormat::Ansi,
+    };
+    // -s used to take a colorscheme, so a missing file straight after it most likely still means one
+    let after_squeeze = matches.index_of("squeeze_blank")
+        .and_then(|s| matches.indices_of("FILE")?.position(|i| i == s + 1))
+        .map(|n| files[n]);
+    if let Some(name) = after_squeeze {
+        if name != "-" && !std::path::Path::new(name).exists() {
+            print_error!("{}: no such file. -s is --squeeze-blank like in cat, for a colorscheme use --colorscheme {}", name, name);
+            return Ok(false);
+        }
+    }
+    if matches.is_present("diff") && files.len() != 2 {
+        print_error!("--diff needs exactly two files");
+        return Ok(false);
+    }
+    // each file would be followed forever, so the next one would never be read
+    if matches.is_present("follow") && files.len() > 1 {
+        print_error!("--follow takes only one file");
+        return Ok(false);
+    }
+    let line_ranges = match (matches.values_of("line_range"), matches.value_of("center")) {
+        (Some(values), _) => ranges::LineRanges::new(values.map(|v| ranges::parse(v).unwrap()).collect()),
+        (None, Some(center)) => {
+            let height = matches.value_of("height").unwrap().parse().unwrap();
+            ranges::LineRanges::new(vec![ranges::center(center.parse().unwrap(), height)])
+        },
+        (None, None) => Default::default(),
+    };
+
+    let show_all = matches.is_present("show_all");
+    let cat = plain::CatOptions{
+        number: matches.is_present("numbered"),
+        number_nonblank: matches.is_present("number_nonblank"),
+        show_ends: show_all || matches.is_present("e") || matches.is_present("show_ends"),
+        show_tabs: show_all || matches.is_present("t") || matches.is_present("show_tabs"),
+        squeeze_blank: matches.is_present("squeeze_blank"),
+        show_nonprinting: show_all || matches.is_present("e") || matches.is_present("t") || matches.is_present("show_nonprinting"),
+        preserve_eol: matches.is_present("preserve_eol"),
+        show_eol: matches.is_present("show_eol"),
+    };
+
+    // the daemon sends output over its socket, and the client copies it to stdout as is
+    let mut pager = match matches.value_of("paging") {
+        _ if matches.is_present("daemon") || matches.is_present("client") => None,
+        Some("always") => pager::start(pager::Paging::Always),
+        Some("never") => pager::start(pager::Paging::Never),
+        _ => pager::start(pager::Paging::Auto),
+    };
+    let output = pager::output(&mut pager);
+
+    // without colour nvim is only needed to find the lines that match, or the differences
+    let success = if format == nvim::OutputFormat::Plain && !matches.is_present("only_matching_lines") && !matches.is_present("diff") {
+        plain::cat_files(&files, &cat, &line_ranges, output, matches.is_present("follow"))
+    } else {
+        highlight_files(&matches, &files, cat, format, line_ranges, output)
+    };
+    // the output has gone by now, so the pager sees the end of it
+    if let Some(mut pager) = pager {
+        pager.wait()?;
+    }
+    success
+}
+
+// highlight @files, or whatever else @matches asks for, through nvim
+fn highlight_files(
+        matches: &clap::ArgMatches,
+        files: &[&str],
+        cat: plain::CatOptions,
+        format: nvim::OutputFormat,
+        line_ranges: ranges::LineRanges,
+        output: Box<dyn std::io::Write>,
+        ) -> nvim::NvimResult<bool> {
+
+    let filetype = matches.value_of("filetype");
+    let vimrc = matches.value_of("vimrc");
+    let colorscheme = matches.value_of("colorscheme");
+
+    let width = match matches.value_of("terminal_width") {
+        Some(width) => Some(width.parse().unwrap()),
+        None => term::width(),
+    };
+    // a daemon has no idea how wide its clients' terminals are
+    let wrap = match matches.value_of("wrap") {
+        _ if matches.is_present("daemon") => term::Wrap::Never,
+        Some("never") => term::Wrap::Never,
+        Some("character") => term::Wrap::Character(width.unwrap_or(80)),
+        Some("word") => term::Wrap::Word(width.unwrap_or(80)),
+        _ => match width {
+            Some(width) if nix::unistd::isatty(libc::STDOUT_FILENO).unwrap_or(false) => term::Wrap::Character(width),
+            _ => term::Wrap::Never,
+        },
+    };
+
     let options = nvim::NvimOptions{
-        numbered: matches.is_present("numbered"),
+        cat,
         restricted_mode: matches.is_present("restricted_mode"),
+        format,
+        colors: match matches.value_of("colors") {
+            Some("truecolor") => synattr::ColorMode::Truecolor,
+            Some("256") => synattr::ColorMode::Colors256,
+            Some("16") => synattr::ColorMode::Colors16,
+            Some("8") => synattr::ColorMode::Colors8,
+            _ => synattr::ColorMode::detect(),
+        },
+        wrap,
+        tabs: matches.value_of("tabs").map(|n| n.parse().unwrap()),
+        max_buffer_lines: matches.value_of("max_buffer_lines").unwrap().parse().unwrap(),
+        verbose: matches.is_present("verbose"),
+        strict: matches.is_present("strict"),
+    };
+
+    let socket = matches.value_of("socket");
+    if matches.is_present("client") {
+        return daemon::client(socket, files, filetype);
+    }
+
+    let mut process = None;
+    let (stdin, stdout, stdout_fd): rpc::Transport = match matches.value_of("server") {
+        Some(server) => rpc::connect(server)?,
+        None => {
+            let mut child = nvim::Nvim::start_process(vimrc, colorscheme, options)?;
+            // both piped by start_process
+            let stdout = child.stdout.take().unwrap();
+            let stdin = child.stdin.take().unwrap();
+            let fd = stdout.as_raw_fd();
+            process = Some(child);
+            (Box::new(stdin), Box::new(stdout), fd)
+        },
     };
 
-    let process = nvim::Nvim::start_process(vimrc, colorscheme, options);
-    let stdout = process.stdout.unwrap();
-    let stdin = process.stdin.unwrap();
+    let embedded = process.is_some();
+    let success = poller::Poller::new(stdout_fd).and_then(|mut poller| {
+        let mut nvim = nvim::Nvim::new(stdin, stdout, options, embedded)?;
+        nvim.set_output(output);
+        nvim.set_line_ranges(line_ranges);
+        if let Some(pattern) = matches.value_of("search") {
+            let context = matches.value_of("context").unwrap().parse().unwrap();
+            let context = if matches.is_present("only_matching_lines") { Some(context) } else { None };
+            nvim.set_search(pattern, "Search", context)?;
+        }
+        if let Some(values) = matches.values_of("highlight_line") {
+            let marked = ranges::LineRanges::new(values.map(|v| ranges::parse(v).unwrap()).collect());
+            nvim.set_marked_lines(marked, matches.value_of("highlight_group").unwrap())?;
+        }
+        if matches.is_present("daemon") {
+            daemon::serve(socket, &mut nvim, &mut poller)
+        } else if matches.is_present("diff") {
+            let layout = match matches.value_of("diff_layout") {
+                Some("side-by-side") => diff::DiffLayout::SideBySide(width.unwrap_or(80)),
+                _ => diff::DiffLayout::Unified,
+            };
+            let context = matches.value_of("context").unwrap().parse().unwrap();
+            match diff::dump_diff(files[0], files[1], &mut nvim, layout, context) {
+                // the pager was quit early
+                Err(nvim::NvimError::IOError(ref e)) if e.kind() == ErrorKind::BrokenPipe => Ok(true),
+                result => result.map(|()| true),
+            }
+        } else {
+            dump_files(files, &mut nvim, &mut poller, filetype, matches.is_present("patch"), matches.is_present("follow"))
+        }
+    });
+    // nvim quits once it is dropped
+    if let Some(mut process) = process {
+        process.wait()?;
+    }
+    success
+}
 
-    let mut poller = poller::Poller::new(stdout.as_raw_fd())?;
-    let mut nvim = nvim::Nvim::new(stdin, stdout, options)?;
+fn dump_files(
+        files: &[&str],
+        nvim: &mut nvim::Nvim,
+        poller: &mut poller::Poller,
+        filetype: Option<&str>,
+        patch: bool,
+        follow: bool,
+        ) -> nvim::NvimResult<bool> {
 
     let mut success = true;
     for (i, &file) in files.iter().enumerate() {
-        match dump_file(file, &mut nvim, &mut poller, filetype) {
+        match dump_file(file, nvim, poller, filetype, patch, follow) {
             Err(nvim::NvimError::IOError(ref e)) if e.kind() == ErrorKind::BrokenPipe => break,
             Err(nvim::NvimError::IOError(e)) => {
                 // get friendly error message
@@ -147,7 +614,7 @@ fn entrypoint() -> nvim::NvimResult<bool> {
                 // try to continue on ioerrors
             },
             Err(e) => {
-                print_error!("{}: {:?}", file, e);
+                print_error!("{}: {}", file, e);
                 success = false;
                 break;
             },
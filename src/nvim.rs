@@ -2,36 +2,138 @@ extern crate rmp;
 extern crate rmpv;
 extern crate rmp_serde;
 extern crate serde;
+extern crate serde_bytes;
 
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::io::{stdout, Write, Cursor};
-use std::process::{Command, Child, Stdio, ChildStdout, ChildStdin};
+use std::collections::{HashMap, HashSet, BTreeMap, VecDeque};
+use std::io::{Read, Write, Cursor};
+use std::process::{Command, Child, Stdio};
 use std::default::Default;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 use self::rmp_serde::Serializer;
 use self::serde::Serialize;
-use synattr::SynAttr;
-use rpc::{Reader, Writer, MsgId};
+use self::serde::de::DeserializeOwned;
+use synattr::{self, SynAttr, ColorMode};
+use html;
+use json;
+use ranges::LineRanges;
+use eol::{self, Eol};
+use term::{self, Wrap, TabStops};
+use plain::{self, CatOptions};
+use rpc::{Reader, Writer, MsgId, Message, Handle};
 
-const INIT_COMMAND: &str = "set scrolloff=0 mouse= showtabline=0 | NoMatchParen";
+const INIT_COMMAND: &str = "set scrolloff=0 mouse= showtabline=0 laststatus=0 noruler noshowcmd noshowmode | NoMatchParen";
+// defines _nvim_cat, which merges syntax, tree-sitter and extmark highlights
+const HIGHLIGHT_LUA: &str = include_str!("highlight.lua");
+// synids from here on are made up by us for one highlight drawn over another
+const OVERLAY_SYNID: usize = 1 << 30;
+// printed lines are deleted from the buffer in batches of this many
+const TRIM_LINES: usize = 1000;
+// nvim_get_option_value is the newest api we use
+const MIN_API_LEVEL: u64 = 9;
+const MIN_NVIM_VERSION: &str = "0.7";
+// buffer settings so we can swap buffers in a shared session without prompts or swap files
+pub const SCRATCH_BUFFER: &str = "setlocal bufhidden=hide noswapfile";
 
 quick_error! {
     #[derive(Debug)]
+    #[allow(clippy::enum_variant_names)]
     pub enum NvimError {
-        RpcError(x: String) { }
-        EncodeError(x: rmp_serde::encode::Error) { from() }
-        DecodeError(x: rmp_serde::decode::Error) { from() }
-        IOError(x: std::io::Error) { from() }
+        NvimNotFound(err: std::io::Error) {
+            display("could not start nvim: {}", err)
+        }
+        NvimTooOld(version: String) {
+            display("nvim >= {} required, found {}", MIN_NVIM_VERSION, version)
+        }
+        // nvim sent back an error for request @id to @method
+        RequestFailed(method: String, id: MsgId, message: String) {
+            display("{} (request {}) failed: {}", method, id, message)
+        }
+        // nvim's answer to request @id to @method was not what we asked for
+        UnexpectedResponse(method: String, id: MsgId, value: rmpv::Value, error: String) {
+            display("unexpected response from nvim to {} (request {}): {}, got {}", method, id, error, value)
+        }
+        InvalidMessage(value: rmpv::Value) {
+            display("invalid message from nvim: {}", value)
+        }
+        // an error message from nvim with --strict
+        ErrorMessage(message: String) {
+            display("nvim: {}", message)
+        }
+        // the --client thread sending a file to the daemon died
+        SenderPanicked {
+            display("the thread sending the input panicked")
+        }
+        EncodeError(x: rmp_serde::encode::Error) {
+            from()
+            display("could not encode a message for nvim: {}", x)
+        }
+        DecodeError(x: rmp_serde::decode::Error) {
+            from()
+            display("could not decode a message from nvim: {}", x)
+        }
+        IOError(x: std::io::Error) {
+            from()
+            display("{}", x)
+        }
     }
 }
 pub type NvimResult<T> = Result<T, NvimError>;
 
+// answers a request or takes a notification from nvim, given its arguments.
+// errors are sent back to nvim as they are
+pub type Handler = Box<dyn FnMut(Vec<rmpv::Value>) -> Result<rmpv::Value, rmpv::Value>>;
+
+// nvim's answer to one of our requests
+pub struct Response {
+    id:     MsgId,
+    method: String,
+    value:  rmpv::Value,
+}
+
+impl Response {
+    // the answer as a T, or an error saying what was wrong with it
+    pub fn decode<T: DeserializeOwned>(self) -> NvimResult<T> {
+        match T::deserialize(self.value.as_ref()) {
+            Ok(value) => Ok(value),
+            Err(e) => Err(self.unexpected(&e.to_string())),
+        }
+    }
+
+    pub fn unexpected(self, error: &str) -> NvimError {
+        NvimError::UnexpectedResponse(self.method, self.id, self.value, error.to_string())
+    }
+}
+
+// @key of the msgpack map @map
+fn map_get<'a>(map: &'a rmpv::Value, key: &str) -> Option<&'a rmpv::Value> {
+    map.as_map()?.iter().find(|(k, _)| k.as_str() == Some(key)).map(|(_, v)| v)
+}
+
+// an error to send back to nvim, in the [type, message] form nvim uses itself
+pub fn rpc_error(message: &str) -> rmpv::Value {
+    vec![rmpv::Value::from(0), rmpv::Value::from(message)].into()
+}
+
+// the message of an error from nvim
+fn error_message(error: &rmpv::Value) -> String {
+    match error.as_array().and_then(|e| e.get(1)).and_then(rmpv::Value::as_str) {
+        Some(message) => message.to_string(),
+        None => error.to_string(),
+    }
+}
+
 #[derive(PartialEq, Eq, Debug)]
 struct Line {
     pub lineno: usize,
     pub line: Vec<u8>,
     pub synids: Vec<usize>,
     pub pending: HashSet<usize>,
+    // byte ranges matching --search
+    pub matches: Vec<(usize, usize)>,
+    pub eol: Eol,
 }
 
 impl Ord for Line {
@@ -42,16 +144,21 @@ impl Ord for Line {
 
 impl PartialOrd for Line {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(&other))
+        Some(self.cmp(other))
     }
 }
 
 pub enum Callback {
-    AddLine(usize, Vec<u8>),
-    GetSynId(usize, Vec<u8>),
+    // the lines from the first lineno on
+    AddChunk(usize, Vec<eol::Line>),
     GetSynAttr(usize),
 }
 
+// (start, end, synid) runs of the bytes of a line
+type Spans = Vec<(usize, usize, usize)>;
+// spans and search matches for each line of a chunk, plus 'tabstop' and 'vartabstop'
+type ChunkHighlights = (Vec<(Spans, Vec<(usize, usize)>)>, (usize, Vec<usize>));
+
 enum FutureSynAttr {
     Result(SynAttr),
     Pending,
@@ -66,6 +173,24 @@ fn char_is_control(c: u8) -> bool {
     }
 }
 
+// one synid per byte of @line from the spans nvim gave for it
+fn span_synids(spans: &[(usize, usize, usize)], line: &[u8]) -> Vec<usize> {
+    let mut synids = vec![0; line.len()];
+    for &(start, end, synid) in spans {
+        let end = end.min(line.len());
+        if start < end {
+            synids[start..end].fill(synid);
+        }
+    }
+    // highlight control chars with 1 (specialkey)
+    for (synid, &c) in synids.iter_mut().zip(line) {
+        if char_is_control(c) {
+            *synid = 1;
+        }
+    }
+    synids
+}
+
 fn push_print_str(vec: &mut Vec<u8>, bytes: &[u8]) {
     let mut start = 0;
     for (i, c) in bytes.iter().enumerate() {
@@ -80,10 +205,91 @@ fn push_print_str(vec: &mut Vec<u8>, bytes: &[u8]) {
     vec.extend_from_slice(&bytes[start..]);
 }
 
+// (start, end, synid) for each run of equal @synids
+fn synid_runs(synids: &[usize]) -> impl Iterator<Item=(usize, usize, usize)> + '_ {
+    let mut start = 0;
+    std::iter::from_fn(move || {
+        let &synid = synids.get(start)?;
+        let end = start + synids[start..].iter().take_while(|&&id| id == synid).count();
+        let run = (start, end, synid);
+        start = end;
+        Some(run)
+    })
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    Ansi,
+    // ansi with the colour turned off, so just the text
+    Plain,
+    Html,
+    Json,
+}
+
 #[derive(Copy, Clone)]
 pub struct NvimOptions {
-    pub numbered: bool,
+    pub cat: CatOptions,
     pub restricted_mode: bool,
+    pub format: OutputFormat,
+    pub colors: ColorMode,
+    // only applies to ansi output
+    pub wrap: Wrap,
+    // fixed tab width, 0 to leave tabs alone, or None for the buffer's 'tabstop'
+    pub tabs: Option<usize>,
+    // printed lines kept in the buffer for the syntax of later ones, 0 to keep them all
+    pub max_buffer_lines: usize,
+    // print nvim's messages to stderr
+    pub verbose: bool,
+    // stop at the first error message from nvim
+    pub strict: bool,
+}
+
+// what nvim has shown through ext_messages
+#[derive(Default)]
+struct Messages {
+    // errors not yet reported, with --strict
+    errors: Vec<String>,
+    // nvim is waiting for a key
+    prompt: bool,
+}
+
+impl Messages {
+    fn show(&mut self, kind: &str, text: String, options: &NvimOptions) {
+        match kind {
+            "return_prompt" | "confirm" | "confirm_sub" => self.prompt = true,
+            "emsg" | "echoerr" | "lua_error" | "rpc_error" if options.strict => self.errors.push(text),
+            "emsg" | "echoerr" | "lua_error" | "rpc_error" | "wmsg" if options.verbose => { print_warning!("nvim: {}", text); },
+            _ if options.verbose && !text.is_empty() => { writeln!(std::io::stderr(), "nvim: {}", text).ok(); },
+            _ => (),
+        }
+    }
+}
+
+// collect the msg_show events from a redraw notification
+fn on_redraw(messages: &RefCell<Messages>, events: &[rmpv::Value], options: &NvimOptions) {
+    let mut messages = messages.borrow_mut();
+    for event in events.iter().filter_map(rmpv::Value::as_array) {
+        if event.first().and_then(rmpv::Value::as_str) != Some("msg_show") {
+            continue
+        }
+        // [kind, [[attr, text], ...], replace_last]
+        for args in event[1..].iter().filter_map(rmpv::Value::as_array) {
+            let kind = args.first().and_then(rmpv::Value::as_str).unwrap_or("");
+            let text = args.get(1)
+                .and_then(rmpv::Value::as_array)
+                .map(|chunks| chunks.iter().filter_map(|chunk| chunk.as_array()?.get(1)?.as_str()).collect())
+                .unwrap_or_default();
+            messages.show(kind, text, options);
+        }
+    }
+}
+
+struct Search {
+    synid: usize,
+    // lines of context around matches with --only-matching-lines, otherwise every line is printed
+    context: Option<usize>,
+    last_match: Option<usize>,
+    last_printed: Option<usize>,
 }
 
 pub struct Nvim {
@@ -93,15 +299,45 @@ pub struct Nvim {
     callbacks:      HashMap<MsgId, Callback>,
     queue:          VecDeque<Option<Line>>,
     pub lineno:     usize,
+    // lines deleted from the top of the buffer
+    buffer_offset:  usize,
     default_attr:   SynAttr,
     normal_attr:    SynAttr,
     scratch_space:  Vec<u8>,
     termguicolors:  bool,
+    fileencodings:  Vec<String>,
+    overlay_synids: HashMap<(usize, usize), usize>,
     hi_linenr:      Option<SynAttr>,
+    options:        NvimOptions,
+    seen_synids:    HashSet<usize>,
+    pub filename:   String,
+    line_ranges:    LineRanges,
+    // lines drawn with an extra highlight over them, and its synid
+    marked_lines:   (LineRanges, usize),
+    // what the rest of the row after the text is cleared in, e.g. the group of a marked line
+    fill_synid:     usize,
+    search:         Option<Search>,
+    // None to print tabs as they are
+    tab_stops:      Option<TabStops>,
+    // SpecialKey and NonText, for cat -v, -T and -E
+    special_synids: (usize, usize),
+    // for cat -s and -b
+    last_blank:     bool,
+    numbered_lines: usize,
+    // number of lines in the file once it has all been read
+    input_end:      Option<usize>,
+    output:         Box<dyn Write>,
+    // for requests and notifications from nvim, by method
+    handlers:       HashMap<String, Handler>,
+    // method of each request still waiting for its response
+    methods:        HashMap<MsgId, String>,
+    messages:       Rc<RefCell<Messages>>,
+    // false if attached to someone else's nvim over --server
+    embedded:       bool,
 }
 
 impl Nvim {
-    pub fn start_process(vimrc: Option<&str>, colorscheme: Option<&str>, options: NvimOptions) -> Child {
+    pub fn start_process(vimrc: Option<&str>, colorscheme: Option<&str>, options: NvimOptions) -> NvimResult<Child> {
         let mut command = Command::new("nvim");
         command.arg("--embed");
         command.arg("-nm");
@@ -121,10 +357,13 @@ impl Nvim {
         command
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
-            .spawn().expect("could not find nvim")
+            .spawn()
+            .map_err(NvimError::NvimNotFound)
     }
 
-    pub fn new(stdin: ChildStdin, stdout: ChildStdout, options: NvimOptions) -> NvimResult<Self> {
+    // @embedded is false when talking to an nvim we did not start,
+    // in which case we work in our own tab and leave the rest of the session alone
+    pub fn new(stdin: Box<dyn Write>, stdout: Box<dyn Read>, options: NvimOptions, embedded: bool) -> NvimResult<Self> {
         let writer = Writer::new(Serializer::new(stdin));
         let reader = Reader::new(stdout);
 
@@ -135,114 +374,248 @@ impl Nvim {
             callbacks: HashMap::new(),
             queue: VecDeque::new(),
             lineno: 0,
+            buffer_offset: 0,
             termguicolors: false,
+            fileencodings: vec![],
+            overlay_synids: HashMap::new(),
             default_attr: Default::default(),
             normal_attr: Default::default(),
             scratch_space: vec![],
             hi_linenr: None,
+            options,
+            seen_synids: HashSet::new(),
+            filename: String::new(),
+            line_ranges: Default::default(),
+            marked_lines: (LineRanges::new(vec![]), 0),
+            fill_synid: 0,
+            search: None,
+            special_synids: (0, 0),
+            last_blank: false,
+            numbered_lines: 0,
+            tab_stops: match options.tabs {
+                Some(0) => None,
+                Some(n) => Some(TabStops::new(n)),
+                None => Some(Default::default()),
+            },
+            input_end: None,
+            output: Box::new(std::io::stdout()),
+            handlers: HashMap::new(),
+            methods: HashMap::new(),
+            messages: Default::default(),
+            embedded,
         };
 
-        // neovim pauses for 1s if there are errors and no ui
-        nvim.ui_attach(100, 100)?;
-        nvim.press_enter()?; // press enter now and then to get past blocking error messages
-        nvim.ui_detach()?;
+        nvim.check_version()?;
+
+        // nvim has no other way to tell us about errors in what we sent it
+        nvim.set_handler("nvim_error_event", Box::new(|args| {
+            let message = args.get(1).and_then(rmpv::Value::as_str).unwrap_or("unknown error");
+            print_error!("nvim: {}", message);
+            Ok(rmpv::Value::Nil)
+        }));
+
+        if embedded {
+            // messages come to us instead of blocking nvim at a prompt,
+            // which it would also pause for 1s on with no ui
+            let messages = Rc::clone(&nvim.messages);
+            nvim.set_handler("redraw", Box::new(move |events| {
+                on_redraw(&messages, &events, &options);
+                Ok(rmpv::Value::Nil)
+            }));
+            // a single row of window, so lines added below it never need redrawing
+            nvim.ui_attach(100, 2)?;
+        } else {
+            nvim.nvim_command(&format!("tabnew | {}", SCRATCH_BUFFER))?;
+        }
+
+        let id = nvim.request("nvim_exec_lua", (HIGHLIGHT_LUA, [0; 0]))?;
+        nvim.wait_for_response(id)?;
 
-        let id = nvim.request("nvim_get_option", ("termguicolors",))?;
-        nvim.termguicolors = nvim.wait_for_response(id)?.as_bool().expect("expected a bool");
+        nvim.termguicolors = nvim.get_option("termguicolors")?;
+
+        let fileencodings: String = nvim.get_option("fileencodings")?;
+        nvim.fileencodings = fileencodings.split(',').map(String::from).collect();
 
         // get synattr of Normal
         let normal = nvim._get_synattr("Normal")?;
         nvim.syn_attr_cache.insert(0, FutureSynAttr::Result(normal.clone()));
         nvim.normal_attr = normal;
 
-        if options.numbered {
+        if options.cat.numbered() {
             nvim.hi_linenr = Some(nvim._get_synattr("LineNR")?);
         }
 
+        if options.cat.changes_text() || options.cat.marks_ends() {
+            let special = (nvim.hl_id("SpecialKey")?, nvim.hl_id("NonText")?);
+            nvim.fetch_synattrs(&[special.0, special.1])?;
+            nvim.special_synids = special;
+        }
+
         Ok(nvim)
     }
 
+    // fail unless nvim has all of the api we use
+    fn check_version(&mut self) -> NvimResult<()> {
+        let id = self.request("nvim_get_api_info", [0; 0])?;
+        let response = self.wait_for_response(id)?;
+        // [channel id, {version: {major, minor, patch, api_level, ...}, ...}]
+        let version = response.value.as_array()
+            .and_then(|info| info.get(1))
+            .and_then(|info| map_get(info, "version"));
+        let number = |key| version.and_then(|v| map_get(v, key)).and_then(rmpv::Value::as_u64);
+
+        match (number("api_level"), number("major"), number("minor"), number("patch")) {
+            (Some(level), _, _, _) if level >= MIN_API_LEVEL => Ok(()),
+            (Some(_), Some(major), Some(minor), Some(patch)) => Err(NvimError::NvimTooOld(format!("{}.{}.{}", major, minor, patch))),
+            _ => Err(response.unexpected("expected api info with a version")),
+        }
+    }
+
+    // the value of option @name, as the current buffer and window see it
+    pub fn get_option<R: DeserializeOwned>(&mut self, name: &str) -> NvimResult<R> {
+        self.call("nvim_get_option_value", (name, BTreeMap::<&str, &str>::new()))
+    }
+
     fn _get_synattr(&mut self, name: &str) -> NvimResult<SynAttr> {
-        let attrs = ("fg", "bg", "bold", "reverse", "italic", "underline");
         let func = format!("synIDattr(synIDtrans(hlID('{}')), v:val, &termguicolors ? 'gui' : 'cterm')", name);
-        let id = self.request("vim_call_function", ("map", (attrs, func) ))?;
-
-        let value = self.wait_for_response(id)?;
-        let attrs = value.as_array().expect("expected an array");
-        Ok(SynAttr::new(
-            attrs[0].as_str().expect("expected a string"),
-            attrs[1].as_str().expect("expected a string"),
-            attrs[2].as_str().expect("expected a string"),
-            attrs[3].as_str().expect("expected a string"),
-            attrs[4].as_str().expect("expected a string"),
-            attrs[5].as_str().expect("expected a string"),
-            &self.normal_attr,
-            self.termguicolors,
-        ))
+        let id = self.request("nvim_call_function", ("map", (synattr::FIELDS, func) ))?;
+
+        let response = self.wait_for_response(id)?;
+        self.parse_synattr(response)
+    }
+
+    fn parse_synattr(&self, response: Response) -> NvimResult<SynAttr> {
+        let fields: [String; 7] = response.decode()?;
+        let attrs: Vec<&str> = fields.iter().map(String::as_str).collect();
+        Ok(SynAttr::new(&attrs, &self.normal_attr, self.termguicolors, self.options.colors))
     }
 
     pub fn ui_attach(&mut self, width: isize, height: isize) -> NvimResult<()> {
-        let opts: rmpv::Value = vec![("rgb".into(), true.into())].into();
+        let opts: rmpv::Value = vec![
+            ("rgb".into(), true.into()),
+            ("ext_linegrid".into(), true.into()),
+            ("ext_messages".into(), true.into()),
+        ].into();
         let id = self.request("nvim_ui_attach", (width, height, opts))?;
         self.wait_for_response(id)?;
         Ok(())
     }
 
-    pub fn ui_detach(&mut self) -> NvimResult<()> {
-        let id = self.request("nvim_ui_detach", &[0; 0])?;
+    pub fn nvim_command(&mut self, command: &str) -> NvimResult<()> {
+        let id = self.request("nvim_command", (command,))?;
         self.wait_for_response(id)?;
         Ok(())
     }
 
-    pub fn press_enter(&mut self) -> NvimResult<()> {
-        self.request("vim_input", ("<CR>",))?;
-        Ok(())
+    // run @command, whose errors (e.g. from an ftplugin) are reported like any other message from nvim
+    pub fn try_command(&mut self, command: &str) -> NvimResult<()> {
+        let error: Option<String> = self.call("nvim_exec_lua", ("return _nvim_cat.try(...)", (command,)))?;
+        if let Some(error) = error {
+            self.messages.borrow_mut().show("emsg", error, &self.options);
+        }
+        self.check_messages()
     }
 
-    pub fn nvim_command(&mut self, command: &str) -> NvimResult<()> {
-        let id = self.request("nvim_command", (command,))?;
-        self.wait_for_response(id)?;
-        Ok(())
+    // act on the messages nvim has shown since last time
+    fn check_messages(&mut self) -> NvimResult<()> {
+        let (prompt, errors) = {
+            let mut messages = self.messages.borrow_mut();
+            (std::mem::take(&mut messages.prompt), std::mem::take(&mut messages.errors))
+        };
+        if prompt {
+            self.request("nvim_input", ("<CR>",))?;
+        }
+        match errors.into_iter().next() {
+            Some(error) => Err(NvimError::ErrorMessage(error)),
+            None => Ok(()),
+        }
     }
 
     pub fn quit(&mut self) -> NvimResult<()> {
-        // don't wait for response, nvim will have quit by then
-        self.request("nvim_command", ("qa!",))?;
+        if self.embedded {
+            // don't wait for response, nvim will have quit by then
+            self.request("nvim_command", ("qa!",))?;
+        } else {
+            // closes our tab too
+            self.nvim_command("bwipe!")?;
+        }
         Ok(())
     }
 
     pub fn buf_set_name(&mut self, name: &str) -> NvimResult<()> {
-        self.request("nvim_buf_set_name", (0, name))?;
+        self.filename = name.to_string();
+        if self.embedded {
+            self.request("nvim_buf_set_name", (0, name))?;
+        } else {
+            // the real file may already be open in the session;
+            // the prefix still leaves the extension for filetype detection
+            self.request("nvim_buf_set_name", (0, format!("nvim-cat://{}", name)))?;
+        }
         Ok(())
     }
 
-    pub fn filetype_detect(&mut self) -> NvimResult<()> {
-        self.request("nvim_command", ("if &ft == '' | silent! filetype detect | endif",))?;
-        Ok(())
+    // the tab stops for a buffer with @tabstop and @vartabstop unless --tabs says otherwise,
+    // None to print tabs as they are
+    pub fn buffer_tab_stops(&self, tabstop: usize, vartabstop: Vec<usize>) -> Option<TabStops> {
+        match self.options.tabs {
+            Some(0) => None,
+            Some(n) => Some(TabStops::new(n)),
+            None => {
+                let mut tabs = TabStops::new(tabstop);
+                tabs.vartabstop = vartabstop.into_iter().filter(|&n| n > 0).collect();
+                Some(tabs)
+            },
+        }
     }
 
-    // add @line to vim
-    pub fn add_line(&mut self, line: String, lineno: usize) -> NvimResult<()> {
-        let id = self.request("buffer_insert", (0, lineno, &[&line]))?;
-        let line: Vec<u8> = Vec::from(&line[..]);
-        self.callbacks.insert(id, Callback::AddLine(lineno, line));
+    // add @lines, the first of which is line @lineno, to vim and highlight them with a single call.
+    // with @detect the filetype is detected from them if it is not known yet
+    pub fn add_lines(&mut self, lines: Vec<eol::Line>, lineno: usize, detect: bool) -> NvimResult<()> {
+        let index = lineno - self.buffer_offset;
+        // send as binary, the lines need not be valid utf-8
+        let text: Vec<_> = lines.iter().map(|(line, _)| serde_bytes::Bytes::new(line)).collect();
+        let wanted: Vec<_> = (lineno..lineno + lines.len()).map(|n| self.line_ranges.contains(n)).collect();
+        let id = self.request("nvim_exec_lua", ("return _nvim_cat.chunk(...)", (index, text, wanted, detect)))?;
+        self.callbacks.insert(id, Callback::AddChunk(lineno, lines));
         Ok(())
     }
 
-    // get syn ids for line @lineno which has length @length
-    fn get_synid(&mut self, lineno: usize, length: usize) -> NvimResult<MsgId> {
-        // use map to reduce rpc calls
-        let expr = format!("map(range(1, {}), \"synID({}, v:val, 0)\")", length+1, lineno+1);
-        self.request("nvim_eval", (expr,))
+    // synchronously highlight the whole of @lines as if they were the file @name,
+    // returning the synids of each line and the tab stops of the file.
+    // only safe when nothing else is in flight
+    pub fn highlight_lines(&mut self, name: &str, lines: &[Vec<u8>]) -> NvimResult<(Vec<Vec<usize>>, Option<TabStops>)> {
+        self.buf_set_name(name)?;
+        self.try_command(&format!("set ft= | doautocmd BufRead {}", name))?;
+
+        let buffer: Vec<_> = lines.iter().map(|l| serde_bytes::Bytes::new(l)).collect();
+        let id = self.request("nvim_buf_set_lines", (0, 0, -1, false, buffer))?;
+        self.wait_for_response(id)?;
+        self.try_command("if &ft == '' | filetype detect | endif")?;
+        let (spans, (tabstop, vartabstop)): (Vec<Spans>, (usize, Vec<usize>)) = self.call("nvim_exec_lua", ("return _nvim_cat.buffer()", [0; 0]))?;
+        let mut spans = spans.into_iter();
+        let synids: Vec<Vec<usize>> = lines.iter().map(|line| span_synids(&spans.next().unwrap_or_default(), line)).collect();
+
+        let set: HashSet<usize> = synids.iter().flatten().cloned().collect();
+        self.fetch_synattrs(&set.into_iter().collect::<Vec<_>>())?;
+        Ok((synids, self.buffer_tab_stops(tabstop, vartabstop)))
     }
 
-    // get @line from vim
-    fn get_line(&mut self, line: Vec<u8>, synids: Vec<usize>) -> NvimResult<&[u8]> {
+    // render @line into the scratch space
+    fn get_line(&mut self, line: &[u8], synids: &[usize]) {
         if line.len() > self.scratch_space.capacity() {
             self.scratch_space.reserve(line.len() - self.scratch_space.capacity());
         }
         self.scratch_space.clear();
 
+        match self.options.format {
+            OutputFormat::Ansi => self.get_ansi_line(line, synids),
+            OutputFormat::Plain => push_print_str(&mut self.scratch_space, line),
+            OutputFormat::Html => self.get_html_line(line, synids),
+            OutputFormat::Json => self.get_json_line(synids),
+        }
+    }
+
+    fn get_ansi_line(&mut self, line: &[u8], synids: &[usize]) {
         let mut ansi = [0u8; 256];
         macro_rules! ansi_write {
             ($buf:ident, $prev:ident, $attr:ident, $field:ident) => ({
@@ -253,18 +626,18 @@ impl Nvim {
             })
         }
 
-        let mut prev_synid = synids.get(0).unwrap_or(&0) + 1;
+        let mut prev_synid = synids.first().unwrap_or(&0) + 1;
         let mut prev_attr = &self.default_attr;
         let mut start = 0;
 
-        let synids = synids.iter().chain(std::iter::once(&0));
+        let synids = synids.iter().chain(std::iter::once(&self.fill_synid));
         for (end, &synid) in synids.enumerate() {
             if synid == prev_synid {
                 continue
             }
 
             let attr = match self.syn_attr_cache.get(&synid) {
-                Some(&FutureSynAttr::Result(ref attr)) => attr,
+                Some(FutureSynAttr::Result(attr)) => attr,
                 _ => unreachable!(),
             };
 
@@ -289,7 +662,130 @@ impl Nvim {
         }
 
         push_print_str(&mut self.scratch_space, &line[start..]);
-        Ok(&self.scratch_space)
+    }
+
+    fn get_html_line(&mut self, line: &[u8], synids: &[usize]) {
+        let mut text = vec![];
+        let mut start = 0;
+        for (run_start, end, synid) in synid_runs(synids) {
+            text.clear();
+            push_print_str(&mut text, &line[run_start..end]);
+            if synid == 0 {
+                html::push_escaped(&mut self.scratch_space, &text);
+            } else {
+                let attr = match self.syn_attr_cache.get(&synid) {
+                    Some(FutureSynAttr::Result(attr)) => attr,
+                    _ => unreachable!(),
+                };
+                html::push_span_start(&mut self.scratch_space, attr);
+                html::push_escaped(&mut self.scratch_space, &text);
+                self.scratch_space.extend_from_slice(b"</span>");
+                self.seen_synids.insert(synid);
+            }
+            start = end;
+        }
+
+        text.clear();
+        push_print_str(&mut text, &line[start..]);
+        html::push_escaped(&mut self.scratch_space, &text);
+    }
+
+    fn get_json_line(&mut self, synids: &[usize]) {
+        for (start, end, synid) in synid_runs(synids) {
+            let attr = match self.syn_attr_cache.get(&synid) {
+                Some(FutureSynAttr::Result(attr)) => attr,
+                _ => unreachable!(),
+            };
+            json::push_token(&mut self.scratch_space, &self.filename, self.lineno+1, start, end, attr);
+        }
+    }
+
+    // only print lines in @ranges
+    pub fn set_line_ranges(&mut self, ranges: LineRanges) {
+        self.line_ranges = ranges;
+    }
+
+    // draw highlight group @group over the lines in @ranges
+    pub fn set_marked_lines(&mut self, ranges: LineRanges, group: &str) -> NvimResult<()> {
+        let synid = self.hl_id(group)?;
+        // fetch it now, nothing can wait for nvim once lines are being printed
+        self.fetch_synattrs(&[synid])?;
+        self.marked_lines = (ranges, synid);
+        Ok(())
+    }
+
+    // draw highlight group @group over matches of the vim regex @pattern,
+    // and only print lines within @context lines of a match if given
+    pub fn set_search(&mut self, pattern: &str, group: &str, context: Option<usize>) -> NvimResult<()> {
+        let id = self.request("nvim_exec_lua", ("_nvim_cat.set_search(...)", (pattern,)))?;
+        self.wait_for_response(id)?;
+        let synid = self.hl_id(group)?;
+        // fetch it now, nothing can wait for nvim once lines are being printed
+        self.fetch_synattrs(&[synid])?;
+        self.search = Some(Search{ synid, context, last_match: None, last_printed: None });
+        Ok(())
+    }
+
+    // all @lines lines of the file have been read
+    pub fn end_input(&mut self, lines: usize) -> NvimResult<()> {
+        self.input_end = Some(lines);
+        self.print_lines()
+    }
+
+    // whether any lines from @lineno on will be printed, if not there is no need to read them
+    pub fn wants_line_from(&self, lineno: usize) -> bool {
+        self.line_ranges.wants_from(lineno)
+    }
+
+    // how many lines after the next one to print may have to come back before it can be
+    pub fn lines_ahead(&self) -> usize {
+        self.search.as_ref().and_then(|search| search.context).unwrap_or(0)
+    }
+
+    pub fn numbered(&self) -> bool {
+        self.options.cat.numbered()
+    }
+
+    pub fn split_cr(&self) -> bool {
+        self.options.cat.split_cr()
+    }
+
+    pub fn fileencodings(&self) -> Vec<String> {
+        self.fileencodings.clone()
+    }
+
+    // send highlighted lines to @output instead of stdout
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    // write anything that has to come before the first line of a file
+    pub fn start_output(&mut self) -> NvimResult<()> {
+        if self.options.format == OutputFormat::Html {
+            self.output.write_all(html::PRE_START)?;
+        }
+        Ok(())
+    }
+
+    // write anything that has to come after the last line of a file
+    pub fn finish_output(&mut self) -> NvimResult<()> {
+        if self.options.format == OutputFormat::Html {
+            // one rule per group name, sorted so the output is stable
+            let mut groups = BTreeMap::new();
+            for synid in self.seen_synids.drain() {
+                if let Some(FutureSynAttr::Result(attr)) = self.syn_attr_cache.get(&synid) {
+                    groups.insert(&attr.name[..], attr);
+                }
+            }
+            if let Some(ref attr) = self.hi_linenr {
+                groups.insert(&attr.name[..], attr);
+            }
+
+            self.output.write_all(html::PRE_END)?;
+            self.output.write_all(html::stylesheet(&self.normal_attr, groups.values().cloned()).as_bytes())?;
+        }
+        self.output.flush()?;
+        Ok(())
     }
 
     // get the syn attr for @synid (cached)
@@ -299,124 +795,471 @@ impl Nvim {
         }
 
         // use map to reduce rpc calls
-        let attrs = ("fg", "bg", "bold", "reverse", "italic", "underline");
-        let id = self.request("vim_call_function", ("map", (attrs, format!("synIDattr(synIDtrans({}), v:val, &termguicolors ? 'gui' : 'cterm')", synid)) ))?;
+        let id = self.request("nvim_call_function", ("map", (synattr::FIELDS, format!("synIDattr(synIDtrans({}), v:val, &termguicolors ? 'gui' : 'cterm')", synid)) ))?;
         self.syn_attr_cache.insert(synid, FutureSynAttr::Pending);
         self.callbacks.insert(id, Callback::GetSynAttr(synid));
         Ok(false)
     }
 
+    // whether @line is a match or within the context of one,
+    // or None if that depends on lines that haven't come back from nvim yet
+    fn near_match(&self, line: &Line) -> Option<bool> {
+        let (context, last_match) = match self.search {
+            Some(Search{ context: Some(context), last_match, .. }) => (context, last_match),
+            _ => return Some(true),
+        };
+        if !line.matches.is_empty() || last_match.is_some_and(|m| line.lineno <= m + context) {
+            return Some(true)
+        }
+        // @line is at the front of the queue, so the following lines come after it
+        for i in 1..=context {
+            if self.input_end.is_some_and(|end| line.lineno + i >= end) {
+                break
+            }
+            match self.queue.get(i) {
+                Some(Some(l)) if !l.matches.is_empty() => return Some(true),
+                Some(Some(_)) => (),
+                _ => return None,
+            }
+        }
+        Some(false)
+    }
+
     fn print_lines(&mut self) -> NvimResult<()> {
-        let stdout = stdout();
-        let mut stdout = stdout.lock();
         loop {
-            match self.queue.get(0) {
-                Some(Some(l)) if l.lineno == self.lineno && l.pending.is_empty() => (),
+            let near_match = match self.queue.front() {
+                Some(Some(l)) if l.lineno == self.lineno && l.pending.is_empty() => self.near_match(l),
                 _ => break,
+            };
+            let near_match = match near_match {
+                Some(near_match) => near_match,
+                None => break,
+            };
+
+            let lineno = self.lineno;
+            let line = self.queue.pop_front().unwrap().unwrap();
+            let squeezed = self.options.cat.squeeze_blank && self.last_blank && line.line.is_empty();
+            let shown = self.line_ranges.contains(lineno) && near_match;
+            if shown && !squeezed {
+                self.last_blank = line.line.is_empty();
+                let mut synids = match self.marked_lines {
+                    (ref ranges, synid) if synid != 0 && !ranges.is_all() && ranges.contains(lineno) => {
+                        // like bat, the whole row is marked and not just the text on it
+                        self.fill_synid = self.overlay_synid(0, synid)?;
+                        // both already cached, so this won't go back to nvim
+                        line.synids.iter().map(|&id| self.overlay_synid(id, synid)).collect::<NvimResult<_>>()?
+                    },
+                    _ => line.synids,
+                };
+
+                if let Some((search_synid, context, last_printed)) = self.search.as_ref().map(|s| (s.synid, s.context, s.last_printed)) {
+                    // separate groups of matches like grep does
+                    if let (Some(_), Some(last_printed)) = (context, last_printed) {
+                        if last_printed + 1 < lineno {
+                            self.write_raw(b"--")?;
+                            self.end_line()?;
+                        }
+                    }
+                    for &(start, end) in &line.matches {
+                        for synid in &mut synids[start..end] {
+                            *synid = self.overlay_synid(*synid, search_synid)?;
+                        }
+                    }
+                }
+
+                self.write_wrapped(lineno, &line.line, &synids, line.eol)?;
+                self.fill_synid = 0;
+            }
+            // a squeezed out blank line doesn't split the lines around it into two groups
+            if shown {
+                if let Some(ref mut search) = self.search {
+                    search.last_printed = Some(lineno);
+                }
+            }
+            if let Some(ref mut search) = self.search {
+                if !line.matches.is_empty() {
+                    search.last_match = Some(lineno);
+                }
+            }
+            self.lineno += 1;
+        }
+        self.trim_buffer()
+    }
+
+    // delete printed lines from the buffer so it does not grow forever.
+    // anything sent to nvim after this uses the new line numbers
+    fn trim_buffer(&mut self) -> NvimResult<()> {
+        let max = self.options.max_buffer_lines;
+        let printed = self.lineno - self.buffer_offset;
+        if max > 0 && printed >= max + TRIM_LINES {
+            let count = printed - max;
+            self.request("nvim_buf_set_lines", (0, 0, count, false, [0u8; 0]))?;
+            self.buffer_offset += count;
+        }
+        Ok(())
+    }
+
+    // line @lineno with its gutter, over as many rows as it takes to fit the terminal
+    fn write_wrapped(&mut self, lineno: usize, text: &[u8], synids: &[usize], eol: Eol) -> NvimResult<()> {
+        let blank = text.is_empty();
+        let (mut text, mut synids) = (Cow::Borrowed(text), Cow::Borrowed(synids));
+        // json offsets are into the original line
+        if self.options.format != OutputFormat::Json {
+            let cat = self.options.cat;
+            if cat.changes_text() || cat.marks_ends() {
+                let (special, nontext) = self.special_synids;
+                let (mut shown, mut shown_synids) = (vec![], vec![]);
+                for (&c, &synid) in text.iter().zip(synids.iter()) {
+                    let len = shown.len();
+                    if plain::push_notation(&mut shown, c, &cat) {
+                        shown_synids.extend(std::iter::repeat_n(special, shown.len() - len));
+                    } else {
+                        shown.push(c);
+                        shown_synids.push(synid);
+                    }
+                }
+                if cat.show_ends && eol != Eol::None {
+                    shown.push(b'$');
+                    shown_synids.push(nontext);
+                }
+                if cat.show_eol {
+                    shown.extend_from_slice(eol.marker());
+                    shown_synids.extend(std::iter::repeat_n(nontext, eol.marker().len()));
+                }
+                text = Cow::Owned(shown);
+                synids = Cow::Owned(shown_synids);
             }
 
-            if let Some(ref attr) = self.hi_linenr {
-                write!(
-                    stdout,
-                    "\x1b[{fg};{bg};{bold};{reverse};{italic};{underline}m{lineno:6}  \x1b[0m",
-                    fg=attr.fg,
-                    bg=attr.bg,
-                    bold=attr.bold,
-                    reverse=attr.reverse,
-                    italic=attr.italic,
-                    underline=attr.underline,
-                    lineno=self.lineno+1,
-                )?;
+            if let Some(ref tabs) = self.tab_stops {
+                if text.contains(&b'\t') {
+                    let (expanded, expanded_synids) = term::expand_tabs(&text, &synids, tabs);
+                    text = Cow::Owned(expanded);
+                    synids = Cow::Owned(expanded_synids);
+                }
             }
+        }
+        let (text, synids) = (&text[..], &synids[..]);
 
-            let line = self.queue.pop_front().unwrap().unwrap();
-            let line = self.get_line(line.line, line.synids)?;
-            stdout.write_all(line)?;
-            stdout.write_all(b"\x1b[K\x1b[0m\n")?;
-            self.lineno += 1;
+        let gutter = if self.hi_linenr.is_some() { 6 + 2 } else { 0 };
+        let tabs = self.tab_stops.clone().unwrap_or_default();
+        let (breaks, last_width) = match (self.options.format, self.options.wrap) {
+            (OutputFormat::Ansi | OutputFormat::Plain, Wrap::Character(width)) => term::wrap(text, Wrap::Character(width.saturating_sub(gutter)), &tabs),
+            (OutputFormat::Ansi | OutputFormat::Plain, Wrap::Word(width)) => term::wrap(text, Wrap::Word(width.saturating_sub(gutter)), &tabs),
+            _ => (vec![], 0),
+        };
+
+        if self.options.cat.number_nonblank {
+            // cat -b counts only the lines it numbers
+            if !blank {
+                self.numbered_lines += 1;
+                self.write_line_number(self.numbered_lines - 1)?;
+            }
+        } else if self.options.cat.squeeze_blank {
+            // and cat -s only the lines it prints
+            self.numbered_lines += 1;
+            self.write_line_number(self.numbered_lines - 1)?;
+        } else {
+            self.write_line_number(lineno)?;
+        }
+        let mut start = 0;
+        for &end in &breaks {
+            self.write_highlighted(&text[start..end], &synids[start..end])?;
+            self.end_row()?;
+            self.write_blank_gutter(6)?;
+            start = end;
+        }
+        self.write_highlighted(&text[start..], &synids[start..])?;
+
+        match self.options.wrap {
+            // clearing the rest of a full row would clear its last character instead
+            Wrap::Character(width) | Wrap::Word(width) if gutter + last_width >= width => self.write_eol(false, eol),
+            _ => self.write_eol(true, eol),
+        }
+    }
+
+    // cat's line number column, which is written just like cat -n does without colour
+    fn write_line_number(&mut self, lineno: usize) -> NvimResult<()> {
+        match (&self.hi_linenr, self.options.format) {
+            (Some(_), OutputFormat::Plain) => write!(self.output, "{:6}\t", lineno+1)?,
+            _ => self.write_gutter(lineno, 6)?,
+        }
+        Ok(())
+    }
+
+    // the line number column, if numbering is on
+    pub fn write_gutter(&mut self, lineno: usize, width: usize) -> NvimResult<()> {
+        match (&self.hi_linenr, self.options.format) {
+            (Some(attr), OutputFormat::Ansi) => write!(
+                self.output,
+                "\x1b[{fg};{bg};{bold};{reverse};{italic};{underline}m{lineno:width$}  \x1b[0m",
+                fg=attr.fg,
+                bg=attr.bg,
+                bold=attr.bold,
+                reverse=attr.reverse,
+                italic=attr.italic,
+                underline=attr.underline,
+                lineno=lineno+1,
+                width=width,
+            )?,
+            (Some(_), OutputFormat::Plain) => write!(self.output, "{:width$}  ", lineno+1, width=width)?,
+            (Some(attr), OutputFormat::Html) => {
+                let mut span = vec![];
+                html::push_span_start(&mut span, attr);
+                self.output.write_all(&span)?;
+                write!(self.output, "{:width$}  </span>", lineno+1, width=width)?;
+            },
+            (_, OutputFormat::Json) | (None, _) => (),
+        }
+        Ok(())
+    }
+
+    // a blank line number column, for rows that have no line of their own
+    pub fn write_blank_gutter(&mut self, width: usize) -> NvimResult<()> {
+        if self.hi_linenr.is_some() && self.options.format != OutputFormat::Json {
+            write!(self.output, "{:width$}  ", "", width=width)?;
         }
         Ok(())
     }
 
+    // @text highlighted with @synids, which must all be in the cache
+    pub fn write_highlighted(&mut self, text: &[u8], synids: &[usize]) -> NvimResult<()> {
+        self.get_line(text, synids);
+        self.output.write_all(&self.scratch_space)?;
+        Ok(())
+    }
+
+    // @text unhighlighted, e.g. a separator
+    pub fn write_raw(&mut self, text: &[u8]) -> NvimResult<()> {
+        match self.options.format {
+            OutputFormat::Ansi => {
+                self.output.write_all(b"\x1b[0m")?;
+                self.output.write_all(text)?;
+            },
+            OutputFormat::Plain | OutputFormat::Html => self.output.write_all(text)?,
+            OutputFormat::Json => (),
+        }
+        Ok(())
+    }
+
+    // @text in highlight @synid that is not part of any line, e.g. a diff header
+    pub fn write_decoration(&mut self, text: &[u8], synid: usize) -> NvimResult<()> {
+        if self.options.format == OutputFormat::Json {
+            return Ok(())
+        }
+        self.fetch_synattrs(&[synid])?;
+        self.write_highlighted(text, &vec![synid; text.len()])?;
+        if self.options.format == OutputFormat::Ansi {
+            self.output.write_all(b"\x1b[0m")?;
+        }
+        Ok(())
+    }
+
+    pub fn end_line(&mut self) -> NvimResult<()> {
+        self.write_eol(true, Eol::Lf)
+    }
+
+    // end a row that the line carries on past
+    fn end_row(&mut self) -> NvimResult<()> {
+        self.write_eol(false, Eol::Lf)
+    }
+
+    // end a row with @eol, or always \n unless --preserve-eol.
+    // @clear clears the rest of the row in the line's last colour
+    fn write_eol(&mut self, clear: bool, eol: Eol) -> NvimResult<()> {
+        let eol = self.options.cat.eol(eol);
+        match self.options.format {
+            OutputFormat::Ansi if clear => self.output.write_all(b"\x1b[K\x1b[0m")?,
+            OutputFormat::Ansi => self.output.write_all(b"\x1b[0m")?,
+            OutputFormat::Plain | OutputFormat::Html => (),
+            OutputFormat::Json => return Ok(()),
+        }
+        self.output.write_all(eol)?;
+        Ok(())
+    }
+
+    // synchronously call @method, only safe when nothing else is in flight
+    pub fn call<T: Serialize, R: DeserializeOwned>(&mut self, method: &str, args: T) -> NvimResult<R> {
+        let id = self.request(method, args)?;
+        self.wait_for_response(id)?.decode()
+    }
+
+    // id of the highlight group @name
+    pub fn hl_id(&mut self, name: &str) -> NvimResult<usize> {
+        self.call("nvim_call_function", ("hlID", (name,)))
+    }
+
+    // make sure the syn attrs of @synids are in the cache
+    pub fn fetch_synattrs(&mut self, synids: &[usize]) -> NvimResult<()> {
+        for &synid in synids {
+            self.get_synattr(synid)?;
+        }
+        while synids.iter().any(|id| matches!(self.syn_attr_cache.get(id), Some(FutureSynAttr::Pending))) {
+            self.process_event()?;
+        }
+        Ok(())
+    }
+
+    // a synid standing for @top drawn over @base
+    pub fn overlay_synid(&mut self, base: usize, top: usize) -> NvimResult<usize> {
+        if top == 0 {
+            return Ok(base)
+        }
+        if let Some(&synid) = self.overlay_synids.get(&(base, top)) {
+            return Ok(synid)
+        }
+
+        self.fetch_synattrs(&[base, top])?;
+        let attr = match (self.syn_attr_cache.get(&base), self.syn_attr_cache.get(&top)) {
+            (Some(FutureSynAttr::Result(base)), Some(FutureSynAttr::Result(top))) => {
+                base.overlay(top, &self.normal_attr, self.termguicolors, self.options.colors)
+            },
+            _ => unreachable!(),
+        };
+        let synid = OVERLAY_SYNID + self.overlay_synids.len();
+        self.syn_attr_cache.insert(synid, FutureSynAttr::Result(attr));
+        self.overlay_synids.insert((base, top), synid);
+        Ok(synid)
+    }
+
     pub fn request<T: Serialize>(&mut self, command: &str, args: T) -> NvimResult<MsgId> {
-        self.writer.write(command, args)
+        let id = self.writer.write(command, args)?;
+        self.methods.insert(id, command.to_string());
+        Ok(id)
+    }
+
+    // handle requests and notifications for @method from nvim with @handler,
+    // requests for methods without a handler get an error back
+    pub fn set_handler(&mut self, method: &str, handler: Handler) {
+        self.handlers.insert(method.to_string(), handler);
+    }
+
+    // read the next message from nvim, returning it if it is a response.
+    // requests from nvim are answered straight away so that nvim is never left waiting on us
+    fn read_response(&mut self) -> NvimResult<Option<Response>> {
+        match self.reader.read()? {
+            Message::Response(id, result) => {
+                let method = self.methods.remove(&id).unwrap_or_default();
+                match result {
+                    Ok(value) => Ok(Some(Response{ id, method, value })),
+                    Err(error) => Err(NvimError::RequestFailed(method, id, error_message(&error))),
+                }
+            },
+            Message::Request(id, method, args) => {
+                let result = match self.handlers.get_mut(&method) {
+                    Some(handler) => handler(args),
+                    None => Err(rpc_error(&format!("nvim-cat has no method {}", method))),
+                };
+                self.writer.respond(id, result)?;
+                Ok(None)
+            },
+            Message::Notification(method, args) => {
+                if let Some(handler) = self.handlers.get_mut(&method) {
+                    handler(args).ok();
+                }
+                self.check_messages()?;
+                Ok(None)
+            },
+        }
     }
 
-    fn wait_for_response(&mut self, id: MsgId) -> NvimResult<rmpv::Value> {
+    fn wait_for_response(&mut self, id: MsgId) -> NvimResult<Response> {
         loop {
-            if let Some((got_id, value)) = self.reader.read()? {
-                if got_id == id {
-                    return Ok(value)
+            if let Some(response) = self.read_response()? {
+                if response.id == id {
+                    return Ok(response)
                 }
             }
         }
     }
 
+    // handle of the current window, buffer or tabpage, as returned by @method
+    fn current(&mut self, method: &str) -> NvimResult<Handle> {
+        let id = self.request(method, [0; 0])?;
+        let response = self.wait_for_response(id)?;
+        match Handle::from_value(&response.value) {
+            Some(handle) => Ok(handle),
+            None => Err(response.unexpected("expected a handle")),
+        }
+    }
+
+    pub fn current_window(&mut self) -> NvimResult<Handle> {
+        self.current("nvim_get_current_win")
+    }
+
     pub fn reset(&mut self) -> NvimResult<()> {
         // self.syn_attr_cache.clear();
         self.queue.clear();
+        // drop callbacks for lines of an abandoned file,
+        // but pending synattrs still need to land in the cache
+        self.callbacks.retain(|_, cb| matches!(cb, Callback::GetSynAttr(_)));
         self.lineno = 0;
-        self.nvim_command("bwipe!")?;
+        self.buffer_offset = 0;
+        self.input_end = None;
+        self.last_blank = false;
+        self.numbered_lines = 0;
+        if let Some(ref mut search) = self.search {
+            search.last_match = None;
+            search.last_printed = None;
+        }
+        if self.embedded {
+            self.nvim_command("bwipe!")?;
+        } else {
+            // wiping the only buffer in our tab would close it
+            self.nvim_command(&format!("enew | {} | bwipe! #", SCRATCH_BUFFER))?;
+        }
+        Ok(())
+    }
+
+    // queue line @lineno to be printed once the attrs of all its synids are known
+    fn queue_line(&mut self, lineno: usize, line: Vec<u8>, eol: Eol, spans: &[(usize, usize, usize)], matches: Vec<(usize, usize)>) -> NvimResult<()> {
+        let (synids, pending, matches) = if self.line_ranges.contains(lineno) {
+            let synids = span_synids(spans, &line);
+            let matches = matches.into_iter().filter(|&(start, end)| start <= end && end <= line.len()).collect();
+            let mut pending = HashSet::new();
+            for (_, _, synid) in synid_runs(&synids) {
+                if !self.get_synattr(synid)? {
+                    pending.insert(synid);
+                }
+            }
+            (synids, pending, matches)
+        } else {
+            // only there for the syntax state of later lines, so it was not highlighted
+            (vec![], HashSet::new(), vec![])
+        };
+
+        let index = lineno - self.lineno;
+        for _ in self.queue.len()..=index {
+            self.queue.push_back(None);
+        }
+        self.queue[index] = Some(Line{lineno, line, synids, pending, matches, eol});
         Ok(())
     }
 
     pub fn process_event(&mut self) -> NvimResult<()> {
-        if let Some((id, value)) = self.reader.read()? {
-            if let Some(cb) = self.callbacks.remove(&id) {
+        if let Some(response) = self.read_response()? {
+            if let Some(cb) = self.callbacks.remove(&response.id) {
                 match cb {
-                    Callback::AddLine(lineno, line) => {
-                        let id = self.get_synid(lineno, line.len())?;
-                        self.callbacks.insert(id, Callback::GetSynId(lineno, line));
-                    },
-                    Callback::GetSynId(lineno, line) => {
-                        let synids: Vec<usize> = value
-                            .as_array()
-                            .expect("expected an array")
-                            .iter()
-                            .zip(&line)
-                            // highlight control chars with 1 (specialkey)
-                            .map(|(id, c)| if char_is_control(*c) { 1 } else { id.as_u64().expect("expected int") as usize } )
-                            .collect();
-
-                        let mut set = HashSet::new();
-                        for &id in synids.iter() {
-                            if ! self.get_synattr(id)? {
-                                set.insert(id);
-                            }
-                        }
-                        let should_print = lineno == self.lineno && set.is_empty();
+                    Callback::AddChunk(lineno, lines) => {
+                        let (highlights, (tabstop, vartabstop)): ChunkHighlights = response.decode()?;
+                        // the filetype may have changed them
+                        self.tab_stops = self.buffer_tab_stops(tabstop, vartabstop);
 
-                        let index = lineno - self.lineno;
-                        for _ in self.queue.len()..=index {
-                            self.queue.push_back(None);
-                        }
-                        let line = Line{lineno, line, synids, pending: set};
-                        self.queue[index] = Some(line);
-
-                        if should_print {
-                            self.print_lines()?;
+                        let mut highlights = highlights.into_iter();
+                        for (i, (line, eol)) in lines.into_iter().enumerate() {
+                            let (spans, matches) = highlights.next().unwrap_or_default();
+                            self.queue_line(lineno + i, line, eol, &spans, matches)?;
                         }
+                        // these lines may be the context an earlier one was waiting on
+                        self.print_lines()?;
                     },
                     Callback::GetSynAttr(synid) => {
-                        let attrs = value.as_array().expect("expected an array");
-                        let attrs = SynAttr::new(
-                            attrs[0].as_str().expect("expected a string"),
-                            attrs[1].as_str().expect("expected a string"),
-                            attrs[2].as_str().expect("expected a string"),
-                            attrs[3].as_str().expect("expected a string"),
-                            attrs[4].as_str().expect("expected a string"),
-                            attrs[5].as_str().expect("expected a string"),
-                            &self.normal_attr,
-                            self.termguicolors,
-                        );
+                        let attrs = self.parse_synattr(response)?;
                         self.syn_attr_cache.insert(synid, FutureSynAttr::Result(attrs));
 
                         let mut should_print = false;
-                        for line in self.queue.iter_mut() {
-                            if let Some(line) = line {
-                                if line.pending.remove(&synid) && line.lineno == self.lineno && line.pending.is_empty() {
-                                    should_print = true;
-                                }
+                        for line in self.queue.iter_mut().flatten() {
+                            if line.pending.remove(&synid) && line.lineno == self.lineno && line.pending.is_empty() {
+                                should_print = true;
                             }
                         }
 
@@ -0,0 +1,66 @@
+use std::env;
+use std::io::{self, Write, LineWriter};
+use std::path::Path;
+use std::process::{Command, Child, Stdio};
+use nix;
+use libc;
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum Paging {
+    // only if stdout is a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+// whether less @arg passes escape sequences through
+fn shows_colours(arg: &str) -> bool {
+    match arg.strip_prefix("--") {
+        Some(long) => long.eq_ignore_ascii_case("raw-control-chars"),
+        None => arg.starts_with('-') && arg.contains(['R', 'r']),
+    }
+}
+
+// $NVIM_CAT_PAGER or $PAGER split into words, less by default
+fn command() -> Option<Command> {
+    let pager = env::var("NVIM_CAT_PAGER").or_else(|_| env::var("PAGER")).unwrap_or_else(|_| "less".to_string());
+    let mut words = pager.split_whitespace();
+    let program = words.next()?;
+    let mut args: Vec<&str> = words.collect();
+
+    // keep our colours and quit straight away if it all fits on one screen.
+    // whatever else less is given, without -R or -r it shows the colours as ^[[31m
+    if Path::new(program).file_name().is_some_and(|name| name == "less") {
+        if args.is_empty() {
+            args.push("-RF");
+        } else if !args.iter().any(|arg| shows_colours(arg)) {
+            args.push("-R");
+        }
+    }
+    let mut command = Command::new(program);
+    command.args(args).stdin(Stdio::piped());
+    Some(command)
+}
+
+// start the pager if @paging calls for it.
+// if it can't be started, output just goes to stdout
+pub fn start(paging: Paging) -> Option<Child> {
+    let wanted = match paging {
+        Paging::Always => true,
+        Paging::Never => false,
+        Paging::Auto => nix::unistd::isatty(libc::STDOUT_FILENO).unwrap_or(false),
+    };
+    if !wanted {
+        return None
+    }
+    command()?.spawn().ok()
+}
+
+// where output should go, the pager if there is one
+pub fn output(pager: &mut Option<Child>) -> Box<dyn Write> {
+    match pager.as_mut().and_then(|pager| pager.stdin.take()) {
+        // line buffered like stdout
+        Some(stdin) => Box::new(LineWriter::new(stdin)),
+        None => Box::new(io::stdout()),
+    }
+}
@@ -0,0 +1,280 @@
+use std::io::{BufRead, BufReader, Read};
+use encoding;
+use nvim;
+use term;
+
+enum Kind {
+    // not part of any file, in the given highlight
+    Header(usize),
+    // line @index of the file's buffer, shown in @diff highlight
+    Code{ index: usize, lineno: usize, diff: usize },
+}
+
+struct PatchLine {
+    text: Vec<u8>,
+    kind: Kind,
+    lineno: usize,
+}
+
+// the highlight ids used for each kind of line, as nvim's diff syntax does
+struct Groups {
+    file: usize,
+    index: usize,
+    hunk: usize,
+    commit: usize,
+    add: usize,
+    delete: usize,
+}
+
+// files that are patches going by the name
+pub fn is_patch_name(name: &str) -> bool {
+    name.ends_with(".patch") || name.ends_with(".diff")
+}
+
+// input that is a patch going by its first @line, e.g. from git diff, git log -p or diff -u
+pub fn is_patch_start(line: &[u8]) -> bool {
+    let line = String::from_utf8_lossy(line);
+    // the word after @prefix is a commit hash
+    let hash_after = |prefix: &str| line.strip_prefix(prefix)
+        .and_then(|rest| rest.split_whitespace().next())
+        .is_some_and(|hash| hash.len() >= 7 && hash.bytes().all(|c| c.is_ascii_hexdigit()));
+
+    line.starts_with("diff --git ")
+        || line.starts_with("diff -")
+        || line.starts_with("--- ")
+        || line.starts_with("Index: ")
+        || hash_after("commit ")
+        // git format-patch
+        || hash_after("From ")
+}
+
+// the file named on a ---/+++ line, without the a/ b/ prefix or any timestamp
+fn parse_filename(line: &[u8]) -> Option<String> {
+    let line = String::from_utf8_lossy(&line[4..]);
+    let name = line.split('\t').next().unwrap_or("").trim_end();
+    if name.is_empty() || name == "/dev/null" {
+        return None
+    }
+    let name = name.strip_prefix("a/").or_else(|| name.strip_prefix("b/")).unwrap_or(name);
+    Some(name.to_string())
+}
+
+// (old start, old count, new start, new count) from @@ -l,s +l,s @@
+fn parse_hunk(line: &[u8]) -> Option<(usize, usize, usize, usize)> {
+    let line = String::from_utf8_lossy(line);
+    let mut parts = line.split(' ').skip(1);
+    let range = |part: Option<&str>, sign: char| -> Option<(usize, usize)> {
+        let part = part?.strip_prefix(sign)?;
+        let mut parts = part.splitn(2, ',');
+        let start = parts.next()?.parse().ok()?;
+        let count = parts.next().map_or(Some(1), |c| c.parse().ok())?;
+        Some((start, count))
+    };
+    let (old_start, old_count) = range(parts.next(), '-')?;
+    let (new_start, new_count) = range(parts.next(), '+')?;
+    Some((old_start, old_count, new_start, new_count))
+}
+
+struct Patcher<'a> {
+    nvim: &'a mut nvim::Nvim,
+    groups: Groups,
+    filename: String,
+    lineno: usize,
+    // lines waiting for the code of the current file to be highlighted
+    pending: Vec<PatchLine>,
+    // code of the current file, with the +/- removed
+    code: Vec<Vec<u8>>,
+    old_name: Option<String>,
+    name: Option<String>,
+    // lines left in the current hunk for each side
+    remaining: (usize, usize),
+    old_lineno: usize,
+    new_lineno: usize,
+}
+
+impl<'a> Patcher<'a> {
+    fn add_code(&mut self, line: Vec<u8>, lineno: usize, diff: usize) {
+        self.code.push(line.get(1..).unwrap_or(&[]).to_vec());
+        let index = self.code.len() - 1;
+        self.pending.push(PatchLine{ text: line, kind: Kind::Code{ index, lineno, diff }, lineno: self.lineno });
+    }
+
+    // part of the current hunk if it is one
+    fn add_hunk_line(&mut self, line: Vec<u8>) -> Result<(), Vec<u8>> {
+        match (line.first(), self.remaining) {
+            (Some(b'+'), (_, n)) if n > 0 => {
+                self.remaining.1 -= 1;
+                self.new_lineno += 1;
+                self.add_code(line, self.new_lineno - 1, self.groups.add);
+            },
+            (Some(b'-'), (n, _)) if n > 0 => {
+                self.remaining.0 -= 1;
+                self.old_lineno += 1;
+                self.add_code(line, self.old_lineno - 1, self.groups.delete);
+            },
+            // some tools strip the space off empty context lines
+            (Some(b' '), (o, n)) | (None, (o, n)) if o > 0 && n > 0 => {
+                self.remaining = (o - 1, n - 1);
+                self.old_lineno += 1;
+                self.new_lineno += 1;
+                self.add_code(line, self.new_lineno - 1, 0);
+            },
+            (Some(b'\\'), _) => {
+                // \ No newline at end of file
+                self.pending.push(PatchLine{ text: line, kind: Kind::Header(0), lineno: self.lineno });
+            },
+            _ => {
+                self.remaining = (0, 0);
+                return Err(line)
+            },
+        }
+        Ok(())
+    }
+
+    fn add_line(&mut self, line: Vec<u8>) -> nvim::NvimResult<()> {
+        let line = if self.remaining != (0, 0) {
+            match self.add_hunk_line(line) {
+                Ok(()) => return Ok(()),
+                Err(line) => line,
+            }
+        } else {
+            line
+        };
+
+        if line.starts_with(b"@@ ") && self.name.is_some() {
+            if let Some((old_start, old_count, new_start, new_count)) = parse_hunk(&line) {
+                self.remaining = (old_count, new_count);
+                self.old_lineno = old_start.max(1);
+                self.new_lineno = new_start.max(1);
+                let group = self.groups.hunk;
+                return self.add_header(line, group)
+            }
+        }
+
+        // anything else starts a new section
+        self.flush()?;
+        let group = if line.starts_with(b"diff ") {
+            self.old_name = None;
+            self.name = None;
+            self.groups.file
+        } else if line.starts_with(b"--- ") {
+            self.old_name = parse_filename(&line);
+            self.groups.file
+        } else if line.starts_with(b"+++ ") {
+            // a deleted file is still highlighted as what it was
+            self.name = parse_filename(&line).or_else(|| self.old_name.clone());
+            self.groups.file
+        } else if line.starts_with(b"index ") {
+            self.groups.index
+        } else if line.starts_with(b"commit ") {
+            self.groups.commit
+        } else {
+            0
+        };
+        self.add_header(line, group)
+    }
+
+    fn add_header(&mut self, line: Vec<u8>, group: usize) -> nvim::NvimResult<()> {
+        self.pending.push(PatchLine{ text: line, kind: Kind::Header(group), lineno: self.lineno });
+        if self.code.is_empty() {
+            // nothing to wait for
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    // highlight the code of the current file and print everything up to here
+    fn flush(&mut self) -> nvim::NvimResult<()> {
+        let (synids, tabs) = if self.code.is_empty() {
+            (vec![], None)
+        } else {
+            let name = self.name.clone().unwrap_or_default();
+            let highlighted = self.nvim.highlight_lines(&name, &self.code)?;
+            self.nvim.reset()?;
+            highlighted
+        };
+
+        for line in std::mem::take(&mut self.pending) {
+            self.nvim.filename = self.filename.clone();
+            self.nvim.lineno = line.lineno;
+            match line.kind {
+                Kind::Header(group) => {
+                    self.nvim.write_blank_gutter(6)?;
+                    let ids = vec![group; line.text.len()];
+                    self.nvim.fetch_synattrs(&[group])?;
+                    self.nvim.write_highlighted(&line.text, &ids)?;
+                },
+                Kind::Code{ index, lineno, diff } => {
+                    self.nvim.write_gutter(lineno, 6)?;
+                    let mut ids = Vec::with_capacity(line.text.len());
+                    if !line.text.is_empty() {
+                        ids.push(self.nvim.overlay_synid(0, diff)?);
+                    }
+                    for &synid in &synids[index] {
+                        ids.push(self.nvim.overlay_synid(synid, diff)?);
+                    }
+                    // the code's tab stops start after the +/-, like they do in the file
+                    match tabs {
+                        Some(ref tabs) if line.text.len() > 1 => {
+                            let (mut text, mut text_ids) = (line.text[..1].to_vec(), ids[..1].to_vec());
+                            let (code, code_ids) = term::expand_tabs(&line.text[1..], &ids[1..], tabs);
+                            text.extend(code);
+                            text_ids.extend(code_ids);
+                            self.nvim.write_highlighted(&text, &text_ids)?;
+                        },
+                        _ => self.nvim.write_highlighted(&line.text, &ids)?,
+                    }
+                },
+            }
+            self.nvim.end_line()?;
+        }
+        self.code.clear();
+        Ok(())
+    }
+}
+
+// highlight the patch read from @input, named @filename, using the syntax of each file it touches
+pub fn dump_patch<R: Read>(filename: &str, input: R, nvim: &mut nvim::Nvim) -> nvim::NvimResult<()> {
+    let groups = Groups {
+        file: nvim.hl_id("Type")?,
+        index: nvim.hl_id("PreProc")?,
+        hunk: nvim.hl_id("Statement")?,
+        commit: nvim.hl_id("Identifier")?,
+        add: nvim.hl_id("DiffAdd")?,
+        delete: nvim.hl_id("DiffDelete")?,
+    };
+
+    let mut input = BufReader::new(encoding::Decoder::new(input, nvim.fileencodings()));
+    let mut patcher = Patcher {
+        nvim,
+        groups,
+        filename: filename.to_string(),
+        lineno: 0,
+        pending: vec![],
+        code: vec![],
+        old_name: None,
+        name: None,
+        remaining: (0, 0),
+        old_lineno: 0,
+        new_lineno: 0,
+    };
+
+    patcher.nvim.start_output()?;
+    let mut line = vec![];
+    loop {
+        line.clear();
+        if input.read_until(b'\n', &mut line)? == 0 {
+            break
+        }
+        if line.last() == Some(&b'\n') {
+            line.pop();
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+        }
+        patcher.add_line(line.clone())?;
+        patcher.lineno += 1;
+    }
+    patcher.flush()?;
+    patcher.nvim.finish_output()
+}
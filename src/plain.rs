@@ -0,0 +1,194 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write, ErrorKind};
+use nix;
+use nvim;
+use ranges::LineRanges;
+use follow;
+use eol::{self, Eol};
+
+// the flags cat has for how lines are shown
+#[derive(Copy, Clone, Default, Debug)]
+pub struct CatOptions {
+    // -n
+    pub number: bool,
+    // -b
+    pub number_nonblank: bool,
+    // -E
+    pub show_ends: bool,
+    // -T
+    pub show_tabs: bool,
+    // -s
+    pub squeeze_blank: bool,
+    // -v
+    pub show_nonprinting: bool,
+    // --preserve-eol, write each line ending as it was
+    pub preserve_eol: bool,
+    // --show-eol
+    pub show_eol: bool,
+}
+
+impl CatOptions {
+    pub fn numbered(&self) -> bool {
+        self.number || self.number_nonblank
+    }
+
+    // whether any bytes of a line might be shown differently
+    pub fn changes_text(&self) -> bool {
+        self.show_tabs || self.show_nonprinting
+    }
+
+    // whether anything is drawn at the end of a line
+    pub fn marks_ends(&self) -> bool {
+        self.show_ends || self.show_eol
+    }
+
+    // a lone \r only ends a line if line endings are kept or shown,
+    // otherwise it is part of the line like in cat
+    pub fn split_cr(&self) -> bool {
+        self.preserve_eol || self.show_eol
+    }
+
+    // what to end a line that ended with @eol with
+    pub fn eol(&self, eol: Eol) -> &'static [u8] {
+        if self.preserve_eol { eol.as_bytes() } else { b"\n" }
+    }
+}
+
+// push how cat shows @c with -v and -T, returns false if it is shown as is
+pub fn push_notation(vec: &mut Vec<u8>, c: u8, options: &CatOptions) -> bool {
+    let c = match c {
+        b'\t' if options.show_tabs => c,
+        b'\t' | b'\n' => return false,
+        0x80..=0xff if options.show_nonprinting => {
+            vec.extend_from_slice(b"M-");
+            c - 0x80
+        },
+        0..=0x1f | 0x7f if options.show_nonprinting => c,
+        _ => return false,
+    };
+    match c {
+        0x7f => vec.extend_from_slice(b"^?"),
+        0..=0x1f => vec.extend_from_slice(&[b'^', c + 0x40]),
+        _ => vec.push(c),
+    }
+    true
+}
+
+// copy @input to @output the way cat does, without going anywhere near nvim
+fn cat<R: Read, W: Write>(input: R, output: &mut W, options: &CatOptions, ranges: &LineRanges) -> io::Result<()> {
+    if !options.numbered() && !options.changes_text() && !options.marks_ends() && !options.squeeze_blank && ranges.is_all() {
+        io::copy(&mut BufReader::new(input), output)?;
+        return Ok(())
+    }
+
+    let mut input = BufReader::new(input);
+    let mut read = vec![];
+    let mut leftover = vec![];
+    let mut lines = VecDeque::new();
+    let mut shown = vec![];
+    let mut lineno = 0;
+    let mut nonblank = 0;
+    let mut printed = 0;
+    let mut last_blank = false;
+    loop {
+        if !ranges.wants_from(lineno) {
+            return Ok(())
+        }
+        while lines.is_empty() {
+            read.clear();
+            if input.read_until(b'\n', &mut read)? == 0 {
+                match eol::last_line(std::mem::take(&mut leftover), options.split_cr()) {
+                    Some(line) => lines.push_back(line),
+                    None => return Ok(()),
+                }
+            } else {
+                leftover.extend_from_slice(&read);
+                let mut split = vec![];
+                leftover = eol::split_lines(std::mem::take(&mut leftover), options.split_cr(), &mut split);
+                lines.extend(split);
+            }
+        }
+        let (mut line, mut ending) = lines.pop_front().unwrap();
+        if ending == Eol::CrLf && !options.split_cr() {
+            // cat keeps the \r as part of the line
+            line.push(b'\r');
+            ending = Eol::Lf;
+        }
+        lineno += 1;
+        if !ranges.contains(lineno - 1) {
+            continue
+        }
+
+        let blank = line.is_empty();
+        if blank && last_blank && options.squeeze_blank {
+            continue
+        }
+        last_blank = blank;
+        printed += 1;
+
+        if options.number_nonblank {
+            if !blank {
+                nonblank += 1;
+                write!(output, "{:6}\t", nonblank)?;
+            }
+        } else if options.number {
+            // cat numbers the lines it prints, so squeezed out ones don't count
+            let number = if options.squeeze_blank { printed } else { lineno };
+            write!(output, "{:6}\t", number)?;
+        }
+
+        if options.changes_text() {
+            shown.clear();
+            for &c in &line {
+                if !push_notation(&mut shown, c, options) {
+                    shown.push(c);
+                }
+            }
+            output.write_all(&shown)?;
+        } else {
+            output.write_all(&line)?;
+        }
+        // cat -E only marks lines that end
+        if options.show_ends && ending != Eol::None {
+            output.write_all(b"$")?;
+        }
+        if options.show_eol {
+            output.write_all(ending.marker())?;
+        }
+        // like cat, a missing newline at the very end stays missing
+        let ending = if ending == Eol::None { b"" } else { options.eol(ending) };
+        output.write_all(ending)?;
+    }
+}
+
+// with @follow, the file is read forever like tail -F
+pub fn cat_files(files: &[&str], options: &CatOptions, ranges: &LineRanges, mut output: Box<dyn Write>, follow: bool) -> nvim::NvimResult<bool> {
+
+    let mut success = true;
+    for &file in files {
+        let result = if file == "-" {
+            cat(io::stdin(), &mut output, options, ranges)
+        } else if follow {
+            follow::Tail::new(file).and_then(|f| cat(f, &mut output, options, ranges))
+        } else {
+            File::open(file).and_then(|f| cat(f, &mut output, options, ranges))
+        };
+
+        match result {
+            Err(ref e) if e.kind() == ErrorKind::BrokenPipe => break,
+            Err(e) => {
+                // get friendly error message
+                let e = nix::errno::Errno::from_i32(e.raw_os_error().unwrap_or(0));
+                print_error!("{}: {}", file, e.desc());
+                success = false;
+            },
+            Ok(()) => (),
+        }
+    }
+    match output.flush() {
+        Err(ref e) if e.kind() == ErrorKind::BrokenPipe => (),
+        result => result?,
+    }
+    Ok(success)
+}
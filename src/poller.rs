@@ -1,17 +1,22 @@
 use epoll;
+use encoding;
 use nvim;
+use eol;
 use std;
-use std::os::unix::io::RawFd;
-use std::io::{Read, ErrorKind, BufReader, BufRead};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::io::{Read, ErrorKind, BufReader, BufRead, Cursor};
 
 pub struct Poller {
     poller: epoll::Poller,
     timeout: i32,
     stdout_fd: RawFd,
     stdin_fd: Option<RawFd>,
+    watch_fd: Option<RawFd>,
+    // stdin has something to read that epoll can't know about
+    stdin_woken: bool,
 }
 
-pub enum PollResult { Stdout, Stdin }
+pub enum PollResult { Stdout, Stdin, Watch }
 
 impl Poller {
     pub fn new(stdout_fd: RawFd) -> nvim::NvimResult<Self> {
@@ -23,11 +28,13 @@ impl Poller {
             timeout: -1,
             stdout_fd,
             stdin_fd: None,
+            watch_fd: None,
+            stdin_woken: false,
         })
     }
 
     pub fn add_stdin(&mut self, stdin_fd: RawFd) -> nvim::NvimResult<()> {
-        self.stdin_fd.take();
+        self.rm_stdin()?;
         self.timeout = match self.poller.add_fd(stdin_fd) {
             Ok(_) => {
                 self.stdin_fd = Some(stdin_fd);
@@ -44,13 +51,40 @@ impl Poller {
         if let Some(fd) = self.stdin_fd.take() {
             self.poller.del_fd(fd)?;
         }
+        // nothing to spin on until stdin is added again
+        self.timeout = -1;
         Ok(())
     }
 
+    // also wait on @fd, e.g. inotify for a followed file
+    pub fn add_watch(&mut self, fd: RawFd) -> nvim::NvimResult<()> {
+        self.rm_watch()?;
+        self.poller.add_fd(fd)?;
+        self.watch_fd = Some(fd);
+        Ok(())
+    }
+
+    pub fn rm_watch(&mut self) -> nvim::NvimResult<()> {
+        if let Some(fd) = self.watch_fd.take() {
+            self.poller.del_fd(fd)?;
+        }
+        Ok(())
+    }
+
+    // have the next call to next() return Stdin, e.g. for input read ahead of time
+    pub fn wake_stdin(&mut self) {
+        self.stdin_woken = true;
+    }
+
     pub fn next(&mut self) -> nvim::NvimResult<PollResult> {
+        if self.stdin_woken {
+            self.stdin_woken = false;
+            return Ok(PollResult::Stdin)
+        }
         let result = match self.poller.next(self.timeout)? {
             fd if fd == self.stdin_fd => PollResult::Stdin,
             Some(fd) if fd == self.stdout_fd => PollResult::Stdout,
+            fd if fd.is_some() && fd == self.watch_fd => PollResult::Watch,
             Some(_) => unreachable!(),
             None => unreachable!(),
         };
@@ -58,6 +92,52 @@ impl Poller {
     }
 }
 
+// longest first line read to see what the input is
+const MAX_PEEK: usize = 4096;
+
+// @head, read from @inner ahead of time to see what the input is, then the rest of @inner
+pub struct Peeked<R> {
+    head: Cursor<Vec<u8>>,
+    inner: R,
+}
+
+impl<R> Peeked<R> where R: Read {
+    // read the first line of @inner a byte at a time, so nothing after it is taken from a pipe
+    pub fn first_line(mut inner: R) -> std::io::Result<Self> {
+        let mut head = vec![];
+        let mut byte = [0];
+        while head.len() < MAX_PEEK && head.last() != Some(&b'\n') {
+            match inner.read(&mut byte) {
+                Ok(0) => break,
+                Ok(_) => head.push(byte[0]),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(Peeked{ head: Cursor::new(head), inner })
+    }
+
+    pub fn head(&self) -> &[u8] {
+        self.head.get_ref()
+    }
+}
+
+impl<R> Read for Peeked<R> where R: Read {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if (self.head.position() as usize) < self.head.get_ref().len() {
+            self.head.read(buf)
+        } else {
+            self.inner.read(buf)
+        }
+    }
+}
+
+impl<R> AsRawFd for Peeked<R> where R: AsRawFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
 struct NBFile<R> {
     inner: R,
     fake_eof: bool,
@@ -74,60 +154,104 @@ impl<R> Read for NBFile<R> where R: Read {
 }
 
 pub struct NBBufReader<R> {
-    inner: BufReader<NBFile<R>>,
+    inner: BufReader<NBFile<encoding::Decoder<R>>>,
     buf: Vec<u8>,
-    leftover: Option<String>,
+    leftover: Vec<u8>,
+    // more may be appended after eof, so don't give up a partial last line
+    follow: bool,
+    // whether a lone \r ends a line
+    split_cr: bool,
 }
 
 impl<R> NBBufReader<R> where R: Read {
-    pub fn new(file: R) -> Self {
+    // lines come out as utf-8 if the encoding is one of @fileencodings, otherwise as raw bytes
+    pub fn new(file: R, fileencodings: Vec<String>) -> Self {
+        let mut file = encoding::Decoder::new(file, fileencodings);
+        file.set_nonblocking(true);
         let file = NBFile{ inner: file, fake_eof: false };
         let reader = std::io::BufReader::new(file);
 
-        NBBufReader{ inner: reader, buf: vec![], leftover: None }
+        NBBufReader{ inner: reader, buf: vec![], leftover: vec![], follow: false, split_cr: false }
     }
 
-    pub fn read_lines(&mut self) -> std::io::Result<Option<Vec<String>>> {
-        let mut lines: Vec<String> = vec![];
+    pub fn set_follow(&mut self, follow: bool) {
+        self.follow = follow;
+        self.inner.get_mut().inner.set_follow(follow);
+    }
+
+    pub fn set_split_cr(&mut self, split_cr: bool) {
+        self.split_cr = split_cr;
+    }
+
+    pub fn get_ref(&self) -> &R {
+        self.inner.get_ref().inner.get_ref()
+    }
+
+    // each line with how it ended
+    pub fn read_lines(&mut self) -> std::io::Result<Option<Vec<eol::Line>>> {
+        let mut lines = vec![];
         let mut eof = true;
         loop {
             self.buf.clear();
-            let len = self.inner.read_until(b'\n', &mut self.buf)?;
+            let len = match self.inner.read_until(b'\n', &mut self.buf) {
+                Ok(len) => len,
+                // read something, just not a whole character yet
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                    eof = false;
+                    0
+                },
+                Err(e) => return Err(e),
+            };
             if len == 0 { break; }
 
             eof = false;
-            let has_newline = self.buf[len - 1] == b'\n';
-            if has_newline {
-                self.buf.pop();
-                if len > 1 && self.buf[len - 2] == b'\r' {
-                    self.buf.pop();
-                }
-            }
-
-            let string = unsafe{ std::str::from_utf8_unchecked(&self.buf) };
-            let string = if let Some(mut leftover) = self.leftover.take() {
-                leftover.push_str(string);
-                leftover
-            } else {
-                string.to_string()
-            };
-
-            if has_newline {
-                lines.push(string);
-            } else {
-                self.leftover = Some(string);
-            }
+            let mut line = std::mem::take(&mut self.leftover);
+            line.extend_from_slice(&self.buf);
+            self.leftover = eol::split_lines(line, self.split_cr, &mut lines);
         }
 
-        self.inner.get_mut().fake_eof = eof;
+        // a followed file may grow, so it has to be read again next time
+        self.inner.get_mut().fake_eof = eof && !self.follow;
         if eof {
-            if let Some(leftover) = self.leftover.take() {
-                lines.push(leftover);
-            } else {
+            if self.follow {
                 return Ok(None)
             }
+            match eol::last_line(std::mem::take(&mut self.leftover), self.split_cr) {
+                Some(line) => lines.push(line),
+                None => return Ok(None),
+            }
         }
 
         Ok(Some(lines))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::Write;
+    use eol::Eol;
+
+    #[test]
+    fn follow_reads_appended_lines() {
+        let path = std::env::temp_dir().join(format!("nvim-cat-follow-{}", std::process::id()));
+        fs::write(&path, "one\n").unwrap();
+
+        let mut file = NBBufReader::new(File::open(&path).unwrap(), vec!["utf-8".to_string()]);
+        file.set_follow(true);
+        assert_eq!(file.read_lines().unwrap(), Some(vec![(b"one".to_vec(), Eol::Lf)]));
+        assert_eq!(file.read_lines().unwrap(), None);
+
+        let mut append = OpenOptions::new().append(true).open(&path).unwrap();
+        append.write_all(b"two\nthr").unwrap();
+        assert_eq!(file.read_lines().unwrap(), Some(vec![(b"two".to_vec(), Eol::Lf)]));
+        assert_eq!(file.read_lines().unwrap(), None);
+
+        // a partial line is held back until the rest of it arrives
+        append.write_all(b"ee\n").unwrap();
+        assert_eq!(file.read_lines().unwrap(), Some(vec![(b"three".to_vec(), Eol::Lf)]));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
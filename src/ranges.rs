@@ -0,0 +1,55 @@
+// which lines of a file to print, 0-based with exclusive ends.
+// no ranges at all means every line
+#[derive(Clone, Default, Debug)]
+pub struct LineRanges(Vec<(usize, usize)>);
+
+impl LineRanges {
+    pub fn new(ranges: Vec<(usize, usize)>) -> Self {
+        LineRanges(ranges)
+    }
+
+    pub fn is_all(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn contains(&self, lineno: usize) -> bool {
+        self.0.is_empty() || self.0.iter().any(|&(start, end)| start <= lineno && lineno < end)
+    }
+
+    // whether line @lineno or anything after it is still wanted
+    pub fn wants_from(&self, lineno: usize) -> bool {
+        self.0.is_empty() || self.0.iter().any(|&(_, end)| lineno < end)
+    }
+}
+
+// START:END, START:, :END or just LINE, 1-based and inclusive like bat
+pub fn parse(range: &str) -> Result<(usize, usize), String> {
+    let number = |n: &str, default: usize| -> Result<usize, String> {
+        if n.is_empty() {
+            return Ok(default)
+        }
+        match n.parse::<usize>() {
+            Ok(0) => Err("line numbers start at 1".to_string()),
+            Ok(n) => Ok(n),
+            Err(_) => Err(format!("invalid line number: {}", n)),
+        }
+    };
+
+    let (start, end) = match range.find(':') {
+        Some(i) => (number(&range[..i], 1)?, number(&range[i+1..], usize::MAX)?),
+        None => {
+            let n = number(range, 0)?;
+            (n, n)
+        },
+    };
+    if start == 0 || end < start {
+        return Err(format!("invalid line range: {}", range))
+    }
+    Ok((start - 1, end))
+}
+
+// @height lines with @line in the middle
+pub fn center(line: usize, height: usize) -> (usize, usize) {
+    let start = line.saturating_sub(1).saturating_sub(height.saturating_sub(1) / 2);
+    (start, start + height.max(1))
+}
@@ -3,14 +3,79 @@ extern crate rmpv;
 extern crate rmp_serde;
 extern crate serde;
 
-use std::io::BufReader;
-use std::process::{ChildStdout, ChildStdin};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
 use self::serde::{Serialize, Deserialize};
 use nvim::NvimError;
 
 pub type MsgId = u32;
-pub type Serializer = rmp_serde::Serializer<ChildStdin>;
-pub type Deserializer = rmp_serde::Deserializer<rmp_serde::decode::ReadReader<BufReader<ChildStdout>>>;
+pub type Serializer = rmp_serde::Serializer<Box<dyn Write>>;
+pub type Deserializer = rmp_serde::Deserializer<rmp_serde::decode::ReadReader<BufReader<Box<dyn Read>>>>;
+pub type Transport = (Box<dyn Write>, Box<dyn Read>, RawFd);
+
+// connect to an nvim started with --listen @address,
+// which is either host:port or the path to a unix socket
+pub fn connect(address: &str) -> io::Result<Transport> {
+    if address.contains(':') && !address.contains('/') {
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+        let fd = stream.as_raw_fd();
+        // without nagle each little write would be its own packet, so send whole messages
+        Ok((Box::new(BufWriter::new(stream.try_clone()?)), Box::new(stream), fd))
+    } else {
+        let stream = UnixStream::connect(address)?;
+        let fd = stream.as_raw_fd();
+        Ok((Box::new(stream.try_clone()?), Box::new(stream), fd))
+    }
+}
+
+// nvim's handles for its objects, which come over as msgpack ext types.
+// nvim takes plain integers for them in arguments
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Handle {
+    Buffer(i64),
+    Window(i64),
+    Tabpage(i64),
+}
+
+impl Handle {
+    pub fn from_value(value: &rmpv::Value) -> Option<Self> {
+        match *value {
+            rmpv::Value::Ext(kind, ref data) => {
+                let id = rmp::decode::read_int(&mut &data[..]).ok()?;
+                match kind {
+                    0 => Some(Handle::Buffer(id)),
+                    1 => Some(Handle::Window(id)),
+                    2 => Some(Handle::Tabpage(id)),
+                    _ => None,
+                }
+            },
+            _ => None,
+        }
+    }
+
+    pub fn id(self) -> i64 {
+        match self {
+            Handle::Buffer(id) | Handle::Window(id) | Handle::Tabpage(id) => id,
+        }
+    }
+}
+
+impl Serialize for Handle {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_i64(self.id())
+    }
+}
+
+pub enum Message {
+    // nvim calling one of our methods, it waits for a response
+    Request(MsgId, String, Vec<rmpv::Value>),
+    // the result or error of one of our requests
+    Response(MsgId, Result<rmpv::Value, rmpv::Value>),
+    Notification(String, Vec<rmpv::Value>),
+}
 
 pub struct Writer {
     msg_id:         MsgId,
@@ -30,37 +95,65 @@ impl Writer {
         self.msg_id += 1;
         let value = ( 0, self.msg_id, command, args );
         value.serialize(&mut self.serializer)?;
+        self.serializer.get_mut().flush()?;
         Ok(self.msg_id)
     }
+
+    // answer request @id from nvim
+    pub fn respond(&mut self, id: MsgId, result: Result<rmpv::Value, rmpv::Value>) -> Result<(), NvimError> {
+        let value = match result {
+            Ok(result) => ( 1, id, rmpv::Value::Nil, result ),
+            Err(error) => ( 1, id, error, rmpv::Value::Nil ),
+        };
+        value.serialize(&mut self.serializer)?;
+        self.serializer.get_mut().flush()?;
+        Ok(())
+    }
 }
 
 impl Reader {
-    pub fn new(reader: ChildStdout) -> Self {
+    pub fn new(reader: Box<dyn Read>) -> Self {
         Reader{deserializer: Deserializer::new(BufReader::new(reader))}
     }
 
-    pub fn read(&mut self) -> Result<Option<(u32, rmpv::Value)>, NvimError> {
-        // let value = rmpv::decode::read_value(&mut self.reader)?;
+    pub fn read(&mut self) -> Result<Message, NvimError> {
         let value: rmpv::Value = Deserialize::deserialize(&mut self.deserializer)?;
+        let mut fields = match value {
+            rmpv::Value::Array(fields) => fields,
+            value => return Err(NvimError::InvalidMessage(value)),
+        };
 
-        if let rmpv::Value::Array(value) = value {
-            match value[0].as_u64().expect("expected an int") {
-                1 => {
-                    if ! value[2].is_nil() {
-                        let error = value[2].as_array().expect("expected an array")[1].as_str().expect("expected a string");
-                        return Err(NvimError::RpcError(error.to_string()))
-                    }
-
-                    let id = value[1].as_u64().expect("expected an int") as u32;
-                    let value = value.into_iter().nth(3).unwrap();
-                    Ok(Some( (id, value) ))
-
+        // the last field is moved out rather than copied, responses can be big
+        let kind = fields.first().and_then(rmpv::Value::as_u64);
+        let last = if fields.len() > 1 { fields.pop() } else { None };
+        let message = match (kind, fields.len(), last) {
+            (Some(0), 3, Some(rmpv::Value::Array(params))) => match (fields[1].as_u64(), fields[2].as_str()) {
+                (Some(id), Some(method)) => Some(Message::Request(id as MsgId, method.to_string(), params)),
+                _ => {
+                    fields.push(rmpv::Value::Array(params));
+                    None
                 },
-                // 2 => None, // notification
-                _ => Ok(None),
-            }
-        } else {
-            panic!("sadf");
-        }
+            },
+            (Some(1), 3, Some(result)) => match fields[1].as_u64() {
+                Some(id) if fields[2].is_nil() => Some(Message::Response(id as MsgId, Ok(result))),
+                Some(id) => Some(Message::Response(id as MsgId, Err(fields.swap_remove(2)))),
+                None => {
+                    fields.push(result);
+                    None
+                },
+            },
+            (Some(2), 2, Some(rmpv::Value::Array(params))) => match fields[1].as_str() {
+                Some(method) => Some(Message::Notification(method.to_string(), params)),
+                None => {
+                    fields.push(rmpv::Value::Array(params));
+                    None
+                },
+            },
+            (_, _, last) => {
+                fields.extend(last);
+                None
+            },
+        };
+        message.ok_or(NvimError::InvalidMessage(rmpv::Value::Array(fields)))
     }
 }
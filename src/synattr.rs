@@ -1,11 +1,93 @@
+// the synIDattr() fields fetched for each highlight group, in order
+pub const FIELDS: [&str; 7] = ["name", "fg", "bg", "bold", "reverse", "italic", "underline"];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Colour {
+    Rgb(u8, u8, u8),
+    Indexed(u8),
+}
+
+// how many colours the terminal can show
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ColorMode {
+    Truecolor,
+    Colors256,
+    Colors16,
+    Colors8,
+}
+
+impl ColorMode {
+    // what the terminal supports going by $COLORTERM or else terminfo
+    pub fn detect() -> Self {
+        match std::env::var("COLORTERM") {
+            Ok(ref c) if c == "truecolor" || c == "24bit" => return ColorMode::Truecolor,
+            _ => (),
+        }
+
+        let colors = std::process::Command::new("tput")
+            .arg("colors")
+            .stderr(std::process::Stdio::null())
+            .output()
+            .ok()
+            .and_then(|output| String::from_utf8(output.stdout).ok())
+            .and_then(|colors| colors.trim().parse::<i32>().ok());
+        match colors {
+            Some(n) if n >= 1 << 24 => ColorMode::Truecolor,
+            Some(n) if n >= 256 => ColorMode::Colors256,
+            Some(n) if n >= 16 => ColorMode::Colors16,
+            Some(n) if n > 0 => ColorMode::Colors8,
+            // no terminfo, assume a modern terminal
+            _ => ColorMode::Colors256,
+        }
+    }
+}
+
+impl Colour {
+    // sgr parameters to set this colour as the foreground (or background if not @fg)
+    fn sgr(self, mode: ColorMode, fg: bool) -> String {
+        let (extended, base, bright) = if fg { (38, 30, 90) } else { (48, 40, 100) };
+        let count = match mode {
+            ColorMode::Truecolor => return match self {
+                Colour::Rgb(r, g, b) => format!("{};2;{};{};{}", extended, r, g, b),
+                Colour::Indexed(n) => format!("{};5;{}", extended, n),
+            },
+            ColorMode::Colors256 => 256,
+            ColorMode::Colors16 => 16,
+            ColorMode::Colors8 => 8,
+        };
+
+        let n = match self {
+            Colour::Indexed(n) if u16::from(n) < count => n,
+            colour => ::color::nearest(colour.rgb(), count),
+        };
+        match n {
+            0..=7 if count <= 16 => format!("{}", base + n),
+            8..=15 if count <= 16 => format!("{}", bright + n - 8),
+            _ => format!("{};5;{}", extended, n),
+        }
+    }
+
+    pub fn rgb(self) -> (u8, u8, u8) {
+        match self {
+            Colour::Rgb(r, g, b) => (r, g, b),
+            Colour::Indexed(n) => ::color::xterm_rgb(n),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct SynAttr {
+    pub name: String,
     pub fg: String,
     pub bg: String,
     pub bold: &'static str,
     pub reverse: &'static str,
     pub italic: &'static str,
     pub underline: &'static str,
+    pub fg_colour: Option<Colour>,
+    pub bg_colour: Option<Colour>,
+    // the synIDattr() values this was made from
+    raw: Vec<String>,
 }
 
 const BOLD: &str = "1";
@@ -19,62 +101,77 @@ const NOUNDERLINE: &str = "24";
 const NOFG: &str = "39";
 const NOBG: &str = "49";
 
-fn parse_colour(string: &str, truecolor: bool) -> Option<String> {
+fn parse_colour(string: &str, truecolor: bool) -> Option<Colour> {
     if string.is_empty() { return None; }
 
-    if string.starts_with('#') {
+    if let Some(hex) = string.strip_prefix('#') {
         // rgb
-        let i = i64::from_str_radix(&string[1..], 16).expect("expected a hex string");
-        return Some(format!("2;{};{};{}", i>>16, (i>>8)&0xff, i&0xff));
+        let i = i64::from_str_radix(hex, 16).ok()?;
+        return Some(Colour::Rgb((i>>16) as u8, ((i>>8)&0xff) as u8, (i&0xff) as u8));
     }
 
     if let Ok(n) = string.parse::<u8>() {
-        return Some(format!("5;{}", n));
+        return Some(Colour::Indexed(n));
     }
 
     let string = string.to_ascii_lowercase();
     if truecolor {
-        ::color::TRUECOLOR_MAP.get(&string[..]).map(|(r, g, b)| format!("2;{};{};{}", r, g, b))
+        ::color::TRUECOLOR_MAP.get(&string[..]).map(|&(r, g, b)| Colour::Rgb(r, g, b))
     } else {
-        ::color::COLOR_MAP.get(&string[..]).map(|n| format!("5;{}", n))
+        ::color::COLOR_MAP.get(&string[..]).map(|&n| Colour::Indexed(n))
     }
 }
 
 
 impl SynAttr {
-    pub fn new(
-        fg: &str,
-        bg: &str,
-        bold: &str,
-        reverse: &str,
-        italic: &str,
-        underline: &str,
-        default: &SynAttr,
-        truecolor: bool,
-    ) -> Self {
-        let fg = parse_colour(fg, truecolor);
-        let bg = parse_colour(bg, truecolor);
+    // @attrs are the synIDattr() values in the order of FIELDS,
+    // @truecolor is whether they are the gui (rather than cterm) values
+    pub fn new(attrs: &[&str], default: &SynAttr, truecolor: bool, mode: ColorMode) -> Self {
+        let fg = parse_colour(attrs[1], truecolor);
+        let bg = parse_colour(attrs[2], truecolor);
 
         SynAttr{
-            fg: if let Some(fg) = fg { format!("38;{}", fg) } else { default.fg.to_string() },
-            bg: if let Some(bg) = bg { format!("48;{}", bg) } else { default.bg.to_string() },
-            bold: if !bold.is_empty() { BOLD } else { default.bold },
-            reverse: if !reverse.is_empty() { REVERSE } else { default.reverse },
-            italic: if !italic.is_empty() { ITALIC } else { default.italic },
-            underline: if !underline.is_empty() { UNDERLINE } else { default.underline },
+            name: attrs[0].to_string(),
+            fg: if let Some(fg) = fg { fg.sgr(mode, true) } else { default.fg.to_string() },
+            bg: if let Some(bg) = bg { bg.sgr(mode, false) } else { default.bg.to_string() },
+            bold: if !attrs[3].is_empty() { BOLD } else { default.bold },
+            reverse: if !attrs[4].is_empty() { REVERSE } else { default.reverse },
+            italic: if !attrs[5].is_empty() { ITALIC } else { default.italic },
+            underline: if !attrs[6].is_empty() { UNDERLINE } else { default.underline },
+            fg_colour: fg.or(default.fg_colour),
+            bg_colour: bg.or(default.bg_colour),
+            raw: attrs.iter().map(|a| a.to_string()).collect(),
         }
     }
+
+    // @top drawn over this, e.g. a diff background over syntax,
+    // anything @top leaves unset shows through
+    pub fn overlay(&self, top: &SynAttr, default: &SynAttr, truecolor: bool, mode: ColorMode) -> Self {
+        let name = format!("{} {}", self.name, top.name);
+        let mut attrs = vec![&name[..]];
+        attrs.extend(self.raw.iter().zip(&top.raw).skip(1).map(|(a, b)| if b.is_empty() { &a[..] } else { &b[..] }));
+        SynAttr::new(&attrs, default, truecolor, mode)
+    }
+
+    pub fn is_bold(&self) -> bool { self.bold == BOLD }
+    pub fn is_reverse(&self) -> bool { self.reverse == REVERSE }
+    pub fn is_italic(&self) -> bool { self.italic == ITALIC }
+    pub fn is_underline(&self) -> bool { self.underline == UNDERLINE }
 }
 
 impl std::default::Default for SynAttr {
     fn default() -> Self {
         SynAttr{
+            name: String::new(),
             fg: NOFG.to_string(),
             bg: NOBG.to_string(),
             bold: NOBOLD,
             reverse: NOREVERSE,
             italic: NOITALIC,
             underline: NOUNDERLINE,
+            fg_colour: None,
+            bg_colour: None,
+            raw: vec![String::new(); FIELDS.len()],
         }
     }
 }
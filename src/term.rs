@@ -0,0 +1,141 @@
+extern crate unicode_width;
+
+use std::env;
+use std::str;
+use libc;
+use self::unicode_width::UnicodeWidthChar;
+
+// 'tabstop' and 'vartabstop' of a buffer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TabStops {
+    pub tabstop: usize,
+    pub vartabstop: Vec<usize>,
+}
+
+impl TabStops {
+    pub fn new(tabstop: usize) -> Self {
+        TabStops{ tabstop: tabstop.max(1), vartabstop: vec![] }
+    }
+
+    // columns from @col to the next tab stop
+    pub fn width_at(&self, col: usize) -> usize {
+        // with 'vartabstop' the last width repeats
+        let mut start = 0;
+        for (i, &width) in self.vartabstop.iter().enumerate() {
+            if i == self.vartabstop.len() - 1 {
+                return width - (col - start) % width
+            }
+            if col < start + width {
+                return start + width - col
+            }
+            start += width;
+        }
+        self.tabstop - col % self.tabstop
+    }
+}
+
+impl Default for TabStops {
+    // what terminals do
+    fn default() -> Self {
+        TabStops::new(8)
+    }
+}
+
+// columns of the terminal on stdout, or $COLUMNS if it is not one
+pub fn width() -> Option<usize> {
+    let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+    if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0 && size.ws_col > 0 {
+        return Some(size.ws_col as usize)
+    }
+    env::var("COLUMNS").ok().and_then(|c| c.parse().ok())
+}
+
+// (bytes, display columns) of the character at the start of @bytes when printed at column @col.
+// control characters come out as ^X and bytes that are not utf-8 are printed as is
+pub fn char_width(bytes: &[u8], col: usize, tabs: &TabStops) -> (usize, usize) {
+    let len = match bytes[0] {
+        b'\t' => return (1, tabs.width_at(col)),
+        0..=0x1f | 0x7f => return (1, 2),
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        0xf0..=0xf7 => 4,
+        _ => 1,
+    };
+    match bytes.get(..len).and_then(|c| str::from_utf8(c).ok()) {
+        Some(c) => (len, c.chars().next().and_then(|c| c.width()).unwrap_or(0)),
+        None => (1, 1),
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Wrap {
+    Never,
+    // break lines anywhere to fit the width
+    Character(usize),
+    // break lines after whitespace where possible
+    Word(usize),
+}
+
+// byte offsets to break @line at so each row fits @wrap's width,
+// plus the width of the last row
+pub fn wrap(line: &[u8], wrap: Wrap, tabs: &TabStops) -> (Vec<usize>, usize) {
+    let (width, word) = match wrap {
+        Wrap::Never => return (vec![], 0),
+        Wrap::Character(width) => (width.max(1), false),
+        Wrap::Word(width) => (width.max(1), true),
+    };
+
+    let mut breaks = vec![];
+    let (mut i, mut col, mut row_start) = (0, 0, 0);
+    let mut after_space = None;
+    while i < line.len() {
+        let (len, w) = char_width(&line[i..], col, tabs);
+        // a row always gets at least one character, however wide
+        if col + w > width && i > row_start {
+            row_start = match after_space {
+                Some(at) if word && at > row_start => at,
+                _ => i,
+            };
+            breaks.push(row_start);
+            after_space = None;
+
+            // whatever went past the break starts the next row
+            col = 0;
+            let mut k = row_start;
+            while k < i {
+                let (len, w) = char_width(&line[k..], col, tabs);
+                col += w;
+                k += len;
+            }
+            continue
+        }
+
+        if line[i] == b' ' || line[i] == b'\t' {
+            after_space = Some(i + len);
+        }
+        col += w;
+        i += len;
+    }
+    (breaks, col)
+}
+
+// @text with each tab replaced by spaces up to the next tab stop,
+// and @synids to match so the highlighting still lines up
+pub fn expand_tabs(text: &[u8], synids: &[usize], tabs: &TabStops) -> (Vec<u8>, Vec<usize>) {
+    let mut expanded = Vec::with_capacity(text.len());
+    let mut expanded_synids = Vec::with_capacity(synids.len());
+    let (mut i, mut col) = (0, 0);
+    while i < text.len() {
+        let (len, w) = char_width(&text[i..], col, tabs);
+        if text[i] == b'\t' {
+            expanded.extend(std::iter::repeat_n(b' ', w));
+            expanded_synids.extend(std::iter::repeat_n(synids[i], w));
+        } else {
+            expanded.extend_from_slice(&text[i..i+len]);
+            expanded_synids.extend_from_slice(&synids[i..i+len]);
+        }
+        i += len;
+        col += w;
+    }
+    (expanded, expanded_synids)
+}